@@ -19,6 +19,8 @@ lazy_static! {
         |col| Color::RGBA(col.r, col.b, col.g, col.a)
     )
     .unwrap();
+    pub static ref CAR_TEXTURE_GHOST: PngImage = translucent(&CAR_TEXTURE_AI);
+    pub static ref CAR_TEXTURE_GHOST_PLAYER: PngImage = translucent(&CAR_TEXTURE_PLAYER);
     pub static ref SPECIES_TEXTURES: Arc<Mutex<Vec<(PngImage, PngImage)>>> =
         Arc::new(Mutex::new(Vec::new()));
 }
@@ -39,6 +41,14 @@ pub fn load_species(species: usize) {
     }
 }
 
+pub fn translucent(im: &PngImage) -> PngImage {
+    let mut data = im.data.clone();
+    for pixel in data.chunks_mut(4) {
+        pixel[3] /= 3;
+    }
+    PngImage { data, ..*im }
+}
+
 pub fn shift_hue(im: &PngImage, hue_shift: f32, sat_shift: f32) -> PngImage {
     let orig_data = im
         .data
@@ -0,0 +1,64 @@
+use rand::{thread_rng, Rng};
+
+/// Turns frames of any length into fixed simulation steps. Time that doesn't make up a whole step
+/// is kept for the next frame.
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    pub step: f64,
+    pub max_steps: usize, // Per frame. When the simulation can't keep up it slows down instead.
+    accumulator: f64,
+}
+
+impl SimClock {
+    pub fn new(step: f64, max_steps: usize) -> SimClock {
+        SimClock {
+            step,
+            max_steps,
+            accumulator: 0.,
+        }
+    }
+
+    /// How many steps to run for a frame of `dt` seconds
+    pub fn advance(&mut self, dt: f64) -> usize {
+        self.accumulator += dt;
+        let steps = (self.accumulator / self.step).floor() as usize;
+        if steps > self.max_steps {
+            self.accumulator = 0.;
+            return self.max_steps;
+        }
+        self.accumulator -= steps as f64 * self.step;
+        steps
+    }
+
+    /// How far into the next step the frame is, in [0, 1)
+    pub fn interpolation(&self) -> f64 {
+        (self.accumulator / self.step).clamp(0., 1.)
+    }
+}
+
+#[allow(unused)]
+pub fn test_sim_clock() {
+    let mut rng = thread_rng();
+    const STEP: f64 = 0.02;
+
+    // 60 seconds of frames of different lengths, at different speeds
+    for &(frame, speed) in &[(1. / 60., 1.), (1. / 144., 1.), (1. / 24., 3.), (0.013, 7.)] {
+        let mut clock = SimClock::new(STEP, usize::MAX);
+        let mut steps = 0;
+        let mut time = 0.;
+        while time < 60. {
+            let dt = frame * rng.gen_range(0.5, 1.5);
+            steps += clock.advance(dt * speed);
+            time += dt;
+            assert!(clock.interpolation() >= 0. && clock.interpolation() < 1.);
+        }
+
+        let expected = (time * speed / STEP).floor() as usize;
+        assert!(steps == expected || steps + 1 == expected);
+    }
+
+    let mut clock = SimClock::new(STEP, 10);
+    assert_eq!(clock.advance(1.), 10);
+    assert_eq!(clock.interpolation(), 0.);
+    println!("test_sim_clock: ok");
+}
@@ -0,0 +1,156 @@
+use sdl2::keyboard::Keycode;
+use serde_derive::{Deserialize, Serialize};
+
+/// What a driver wants the car to do this step
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Action {
+    pub throttle: f64, // [-1, 1], negative is reverse
+    pub brake: f64,    // [0, 1]
+    pub steer: f64,    // [-1, 1], positive turns towards increasing angle
+}
+
+/// How network outputs are turned into an `Action`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ActionDecoder {
+    /// The original mapping: throttle is atanh of the first output, steering the second
+    Legacy,
+    /// Throttle and steering taken directly from the two outputs
+    Continuous,
+    /// Separate accelerate, brake and steering outputs
+    AccelBrake,
+    /// The largest of nine outputs picks one of left/straight/right × throttle/coast/brake
+    Discrete,
+}
+
+impl ActionDecoder {
+    pub fn nr_outputs(&self) -> usize {
+        match self {
+            ActionDecoder::Legacy | ActionDecoder::Continuous => 2,
+            ActionDecoder::AccelBrake => 3,
+            ActionDecoder::Discrete => 9,
+        }
+    }
+
+    /// The action closest to `action` that can be decoded, to compare the networks' actions with
+    pub fn closest(&self, action: Action) -> Action {
+        match self {
+            ActionDecoder::Legacy | ActionDecoder::Continuous => Action {
+                brake: 0.,
+                ..action
+            },
+            ActionDecoder::AccelBrake => Action {
+                throttle: action.throttle.max(0.),
+                ..action
+            },
+            ActionDecoder::Discrete => {
+                let (throttle, brake) = if action.brake >= 0.5 {
+                    (0., 1.)
+                } else if action.throttle >= 0.5 {
+                    (1., 0.)
+                } else {
+                    (0., 0.)
+                };
+                Action {
+                    throttle,
+                    brake,
+                    steer: action.steer.round(),
+                }
+            }
+        }
+    }
+
+    pub fn decode(&self, res: &[f64]) -> Action {
+        assert_eq!(self.nr_outputs(), res.len());
+
+        match self {
+            ActionDecoder::Legacy => Action {
+                throttle: res[0].atanh().clamp(-40., 40.) / 40.,
+                brake: 0.,
+                steer: res[1],
+            },
+            ActionDecoder::Continuous => Action {
+                throttle: res[0],
+                brake: 0.,
+                steer: res[1],
+            },
+            ActionDecoder::AccelBrake => Action {
+                throttle: (res[0] + 1.) / 2.,
+                brake: (res[1] + 1.) / 2.,
+                steer: res[2],
+            },
+            ActionDecoder::Discrete => {
+                let best =
+                    (0..res.len()).fold(0, |best, i| if res[i] > res[best] { i } else { best });
+
+                let (throttle, brake) = match best % 3 {
+                    0 => (1., 0.),
+                    1 => (0., 0.),
+                    _ => (0., 1.),
+                };
+                Action {
+                    throttle,
+                    brake,
+                    steer: (best / 3) as f64 - 1.,
+                }
+            }
+        }
+    }
+}
+
+/// Keyboard and mouse state of the human driver
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HumanInput {
+    pub forward: bool,
+    pub back: bool,
+    pub left: bool,
+    pub right: bool,
+    pub captured: bool, // Whether the mouse drives
+    pub mouse_throttle: f64,
+    pub mouse_steer: f64,
+}
+
+impl HumanInput {
+    /// Updates the key state, arrows or WASD. Returns whether the key was a driving key.
+    pub fn key(&mut self, key: Keycode, down: bool) -> bool {
+        match key {
+            Keycode::Up | Keycode::W => self.forward = down,
+            Keycode::Down | Keycode::S => self.back = down,
+            Keycode::Left | Keycode::A => self.left = down,
+            Keycode::Right | Keycode::D => self.right = down,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Relative mouse motion, in pixels. Only counts while the mouse is captured.
+    pub fn mouse(&mut self, xrel: i32, yrel: i32) {
+        if self.captured {
+            self.mouse_steer = (self.mouse_steer + xrel as f64 * 0.01).clamp(-1., 1.);
+            self.mouse_throttle = (self.mouse_throttle - yrel as f64 * 0.01).clamp(-1., 1.);
+        }
+    }
+
+    /// What the human wants the car to do. Back brakes while moving forwards and reverses once
+    /// stopped. The keyboard overrides the mouse.
+    pub fn action(&self, speed: f64) -> Action {
+        let axis = |neg: bool, pos: bool| pos as i32 as f64 - neg as i32 as f64;
+        if self.forward || self.back || self.left || self.right || !self.captured {
+            let throttle = axis(self.back, self.forward);
+            Action {
+                throttle: if throttle < 0. && speed > 1. {
+                    0.
+                } else {
+                    throttle
+                },
+                brake: if throttle < 0. && speed > 1. { 1. } else { 0. },
+                steer: axis(self.left, self.right),
+            }
+        } else {
+            Action {
+                throttle: self.mouse_throttle,
+                brake: 0.,
+                steer: self.mouse_steer,
+            }
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use bincode::deserialize_from;
+
+use crate::control::{Action, ActionDecoder, HumanInput};
+use crate::map::Map;
+use crate::neat::Genome;
+use crate::network::Network;
+use crate::replay::Replay;
+use crate::vehicle::VehicleState;
+
+/// What a controller gets to see before it acts
+pub struct Observation<'a> {
+    pub inputs: &'a [f64], // Preprocessed sensors, empty for controllers that don't sense
+    pub outputs: &'a [f64], // From the batched networks, empty without a genome
+    pub car: &'a VehicleState,
+    pub map: &'a Map,
+    pub time: f64, // Into the episode
+    pub human: &'a HumanInput,
+}
+
+/// Drives a car, one step at a time
+pub trait Controller {
+    fn act(&mut self, obs: &Observation) -> Action;
+
+    /// What drives the car, as written in replays and the death log
+    fn kind(&self) -> &'static str;
+
+    /// The genome being evolved, if any. Its network is evaluated in the batch and its outputs
+    /// given in `Observation::outputs`.
+    fn genome(&self) -> Option<&Genome> {
+        None
+    }
+
+    /// The species to colour the car by
+    fn species(&self) -> Option<usize> {
+        None
+    }
+
+    fn is_human(&self) -> bool {
+        self.kind() == "human"
+    }
+
+    /// Whether the car is the baseline the population is compared against
+    fn is_reference(&self) -> bool {
+        false
+    }
+
+    /// Whether the car needs its sensors read
+    fn senses(&self) -> bool {
+        true
+    }
+
+    /// Called when the controller is carried over to the next generation
+    fn reset(&mut self) {}
+}
+
+/// A genome of the population
+pub struct Neat {
+    pub genome: Genome,
+    pub species: usize,
+    pub decoder: ActionDecoder,
+}
+
+impl Controller for Neat {
+    fn act(&mut self, obs: &Observation) -> Action {
+        self.decoder.decode(obs.outputs)
+    }
+
+    fn kind(&self) -> &'static str {
+        "neat"
+    }
+
+    fn genome(&self) -> Option<&Genome> {
+        Some(&self.genome)
+    }
+
+    fn species(&self) -> Option<usize> {
+        Some(self.species)
+    }
+}
+
+/// Keyboard or mouse
+pub struct Human;
+
+impl Controller for Human {
+    fn act(&mut self, obs: &Observation) -> Action {
+        obs.human.action(obs.car.speed)
+    }
+
+    fn kind(&self) -> &'static str {
+        "human"
+    }
+}
+
+/// A genome that drives along without being evolved, like the champion
+pub struct Fixed {
+    pub network: Network,
+    pub decoder: ActionDecoder,
+}
+
+impl Controller for Fixed {
+    fn act(&mut self, obs: &Observation) -> Action {
+        self.decoder.decode(&self.network.evaluate(obs.inputs))
+    }
+
+    fn kind(&self) -> &'static str {
+        "genome"
+    }
+}
+
+/// Plays back the outputs of a recorded car. Only replays what it did, so it only drives the same
+/// way with the same start and step length.
+pub struct Replayed {
+    pub actions: Vec<Action>,
+    pub step: usize,
+}
+
+impl Controller for Replayed {
+    fn act(&mut self, _obs: &Observation) -> Action {
+        let action = self.actions.get(self.step).cloned().unwrap_or_default();
+        self.step += 1;
+        action
+    }
+
+    fn kind(&self) -> &'static str {
+        "replay"
+    }
+
+    fn senses(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        self.step = 0;
+    }
+}
+
+/// Drives along the track without learning anything, for the networks to be compared against.
+/// Steers along the gradient of the distances in `Map::data`, following it `LOOKAHEAD` pixels
+/// ahead and aiming at where it ends up. Slows down for how sharply it has to turn to get there
+/// and for walls in front, and backs up when it can't make the turn going forwards.
+///
+/// The distances are counted in steps along the axes, so the gradient always points diagonally
+/// and near a wall mostly into it. Closer than `CENTRE` to a wall, the part of it towards the
+/// wall is dropped, which leaves the way along the wall, and the path is pushed back towards
+/// the middle instead. Looking ahead costs about a dozen tile lookups per pixel.
+#[derive(Debug, Clone, Default)]
+pub struct Autopilot {
+    reversing: bool,
+}
+
+impl Autopilot {
+    const LOOKAHEAD: f64 = 60.; // Pixels along the gradient to the aim point
+    const STEP: f64 = 2.; // Pixels between points on the way
+    const CENTRE: f64 = 40.; // Distance to a wall under which the path is kept off it
+    const PUSH: f64 = 0.7; // How hard, right next to the wall
+    const MIN_CLEARANCE: f64 = 12.; // From the middle of the car to a wall, on the way to the aim
+    const LATERAL_ACCEL: f64 = 200.; // Pixels per second², cornering harder slides
+    const BRAKE: f64 = 100.; // Deceleration assumed when planning
+    const MAX_SPEED: f64 = 300.;
+    const STEER_GAIN: f64 = 2.; // Steering per radian off the aim point
+    const RANGE: f64 = 80.; // Of the rays looking for walls around the car
+    const ROOM: f64 = 20.; // Pixels from the middle of the car to a wall, before braking distance
+    const REVERSE_OFF: f64 = 0.3; // Radians off the aim point, reversing until it's less
+
+    // The direction and distance to the end of the path. Where there's no gradient, at the start
+    // or between two equally far tiles, the path goes straight on.
+    fn aim(map: &Map, car: &VehicleState) -> Option<(f64, f64)> {
+        // In a passage narrower than MIN_CLEARANCE, it's enough not to get any closer
+        let min_clearance = Autopilot::MIN_CLEARANCE.min(map.clearance(car.pos) - 1.);
+        let mut pos = car.pos;
+        let mut heading = car.dir;
+        let mut travelled = 0.;
+
+        while travelled < Autopilot::LOOKAHEAD {
+            let dir = map.track_direction(pos).unwrap_or(heading);
+            let (mut dx, mut dy) = (dir.cos(), dir.sin());
+
+            let clearance = map.clearance(pos);
+            if clearance < Autopilot::CENTRE {
+                let away = away_from_wall(map, pos);
+                let towards = dx * away.0 + dy * away.1;
+                dx -= towards * away.0;
+                dy -= towards * away.1;
+                let len = dx.hypot(dy);
+                if len > 1e-6 {
+                    dx /= len;
+                    dy /= len;
+                }
+                let push = Autopilot::PUSH * (Autopilot::CENTRE - clearance) / Autopilot::CENTRE;
+                dx += push * away.0;
+                dy += push * away.1;
+            }
+            let len = dx.hypot(dy);
+            if len < 1e-6 {
+                dx = heading.cos();
+                dy = heading.sin();
+            } else {
+                dx /= len;
+                dy /= len;
+            }
+
+            let next = (pos.0 + Autopilot::STEP * dx, pos.1 + Autopilot::STEP * dy);
+            if map.clearance(next) < min_clearance {
+                break;
+            }
+            heading = dy.atan2(dx);
+            pos = next;
+            travelled += Autopilot::STEP;
+        }
+
+        if travelled == 0. {
+            return None;
+        }
+        let (dx, dy) = (pos.0 - car.pos.0, pos.1 - car.pos.1);
+        Some((dy.atan2(dx), dx.hypot(dy)))
+    }
+}
+
+impl Controller for Autopilot {
+    fn act(&mut self, obs: &Observation) -> Action {
+        let car = obs.car;
+        let (angle, dist) = match Autopilot::aim(obs.map, car) {
+            Some(aim) => aim,
+            None => return Action::default(),
+        };
+        let off = angle_diff(angle, car.dir);
+        let steer = (off * Autopilot::STEER_GAIN).clamp(-1., 1.);
+
+        let room = Autopilot::ROOM + car.speed * car.speed / (2. * Autopilot::BRAKE);
+        let ray = |angle: f64| obs.map.cast_ray(car.pos, angle, Autopilot::RANGE).dist;
+        let ahead = ray(car.dir);
+        let corners = ray(car.dir - 0.5).min(ray(car.dir + 0.5)).min(ahead);
+        let behind = ray(car.dir + PI - 0.5)
+            .min(ray(car.dir + PI + 0.5))
+            .min(ray(car.dir + PI));
+        // Stopped in front of a wall, the aim being straight ahead doesn't help either
+        if corners < room && (off.abs() > Autopilot::REVERSE_OFF || car.speed.abs() < 1.) {
+            self.reversing = true;
+        } else if off.abs() < Autopilot::REVERSE_OFF || behind < room {
+            self.reversing = false;
+        }
+        if self.reversing {
+            // Backwards, the steering swings the nose the other way
+            return Action {
+                throttle: -1.,
+                brake: if car.speed > 0. { 1. } else { 0. },
+                steer: -off.signum(),
+            };
+        }
+
+        // The curvature of the arc from where the car is heading to the aim point, and the speed
+        // it can still brake down from before the wall in front
+        let curvature = 2. * off.sin().abs() / dist;
+        let speed = (Autopilot::LATERAL_ACCEL / curvature.max(1e-6))
+            .sqrt()
+            .min((2. * Autopilot::BRAKE * (corners - Autopilot::ROOM).max(0.)).sqrt())
+            .min(Autopilot::MAX_SPEED);
+
+        if car.speed < speed {
+            Action {
+                throttle: 1.,
+                brake: if car.speed < 0. { 1. } else { 0. },
+                steer,
+            }
+        } else {
+            Action {
+                throttle: 0.,
+                brake: ((car.speed - speed) / 20.).min(1.),
+                steer,
+            }
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        "autopilot"
+    }
+
+    fn is_reference(&self) -> bool {
+        true
+    }
+
+    fn senses(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        self.reversing = false;
+    }
+}
+
+// From `b` to `a`, in [-π, π]
+fn angle_diff(a: f64, b: f64) -> f64 {
+    let diff = (a - b) % (2. * PI);
+    if diff > PI {
+        diff - 2. * PI
+    } else if diff < -PI {
+        diff + 2. * PI
+    } else {
+        diff
+    }
+}
+
+// Unit vector in which the clearance grows around `pos`, zero if it doesn't change
+fn away_from_wall(map: &Map, pos: (f64, f64)) -> (f64, f64) {
+    let r = 2.;
+    let dx = map.clearance((pos.0 + r, pos.1)) - map.clearance((pos.0 - r, pos.1));
+    let dy = map.clearance((pos.0, pos.1 + r)) - map.clearance((pos.0, pos.1 - r));
+    let len = dx.hypot(dy);
+    if len > 0. {
+        (dx / len, dy / len)
+    } else {
+        (0., 0.)
+    }
+}
+
+/// An agent in another process. Every step it gets a line of the time and the inputs, comma
+/// separated, and answers with a line of throttle, brake and steering. A new connection marks a
+/// new episode, it's made again for every generation. An agent that takes longer than
+/// `Remote::TIMEOUT` to connect or answer is dropped and the car coasts.
+pub struct Remote {
+    addr: String,
+    conn: Option<(BufReader<TcpStream>, TcpStream)>,
+}
+
+impl Remote {
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn connect(addr: &str) -> Remote {
+        let mut remote = Remote {
+            addr: addr.to_string(),
+            conn: None,
+        };
+        remote.reset();
+        remote
+    }
+
+    fn exchange(&mut self, obs: &Observation) -> Result<Action, String> {
+        let (reader, writer) = self.conn.as_mut().ok_or("Not connected")?;
+
+        let line = Some(&obs.time)
+            .into_iter()
+            .chain(obs.inputs)
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(writer, "{}", line).map_err(|e| e.to_string())?;
+
+        let mut answer = String::new();
+        reader.read_line(&mut answer).map_err(|e| e.to_string())?;
+        let values = answer
+            .trim()
+            .split(',')
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Bad answer {:?}: {}", answer, e))?;
+        match values[..] {
+            [throttle, brake, steer] => Ok(Action {
+                throttle: throttle.clamp(-1., 1.),
+                brake: brake.clamp(0., 1.),
+                steer: steer.clamp(-1., 1.),
+            }),
+            _ => Err(format!("Bad answer {:?}, expected three values", answer)),
+        }
+    }
+}
+
+impl Controller for Remote {
+    fn act(&mut self, obs: &Observation) -> Action {
+        if self.conn.is_none() {
+            return Action::default();
+        }
+        self.exchange(obs).unwrap_or_else(|e| {
+            println!("Remote {} dropped: {}", self.addr, e);
+            self.conn = None;
+            Action::default()
+        })
+    }
+
+    fn kind(&self) -> &'static str {
+        "remote"
+    }
+
+    fn reset(&mut self) {
+        self.conn = self
+            .addr
+            .to_socket_addrs()
+            .and_then(|mut addrs| {
+                addrs
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address"))
+            })
+            .and_then(|addr| TcpStream::connect_timeout(&addr, Remote::TIMEOUT))
+            .and_then(|stream| {
+                // A hung agent would hang the simulation with it
+                stream.set_read_timeout(Some(Remote::TIMEOUT))?;
+                stream.set_write_timeout(Some(Remote::TIMEOUT))?;
+                Ok((BufReader::new(stream.try_clone()?), stream))
+            })
+            .map_err(|e| println!("Can't connect to {}: {}", self.addr, e))
+            .ok();
+    }
+}
+
+/// Makes a controller from a description, for cars sensing `nr_inputs` values:
+///   human
+///   autopilot              See `Autopilot`
+///   genome:<file>          A champion file, like `champion.bc`
+///   replay:<file>:<car>    Outputs of a car in a replay
+///   remote:<host>:<port>
+pub fn from_spec(
+    spec: &str,
+    nr_inputs: usize,
+    decoder: ActionDecoder,
+) -> Result<Box<dyn Controller + Send>, String> {
+    let mut parts = spec.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("human"), None) => Ok(Box::new(Human)),
+        (Some("autopilot"), None) => Ok(Box::new(Autopilot::default())),
+        (Some("genome"), Some(path)) => {
+            let file = File::open(path).map_err(|e| format!("Can't open {}: {}", path, e))?;
+            let (_, _, genome): (usize, f64, Genome) =
+                deserialize_from(file).map_err(|e| format!("Can't read {}: {}", path, e))?;
+            if (genome.nr_ins, genome.nr_outs) != (nr_inputs, decoder.nr_outputs()) {
+                return Err(format!(
+                    "{} has {} inputs and {} outputs, the cars sense {} and the decoder takes {}",
+                    path,
+                    genome.nr_ins,
+                    genome.nr_outs,
+                    nr_inputs,
+                    decoder.nr_outputs()
+                ));
+            }
+            Ok(Box::new(Fixed {
+                network: Network::compile(&genome),
+                decoder,
+            }))
+        }
+        (Some("replay"), Some(rest)) => {
+            let (path, car) = match rest.rfind(':') {
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => return Err(format!("Expected replay:<file>:<car>, got {}", spec)),
+            };
+            let car = car
+                .parse::<usize>()
+                .map_err(|e| format!("Bad car {:?}: {}", car, e))?;
+            let replay = Replay::load(path).map_err(|e| format!("Can't load {}: {}", path, e))?;
+            let track = replay
+                .tracks
+                .get(car)
+                .ok_or_else(|| format!("No car {} in {}", car, path))?;
+            if track
+                .frames
+                .iter()
+                .any(|x| x.outputs.len() != decoder.nr_outputs())
+            {
+                return Err(format!("Car {} in {} has no outputs to replay", car, path));
+            }
+
+            let actions = track
+                .frames
+                .iter()
+                .map(|x| {
+                    let outputs = x.outputs.iter().map(|&x| x as f64).collect::<Vec<_>>();
+                    decoder.decode(&outputs)
+                })
+                .collect();
+            Ok(Box::new(Replayed { actions, step: 0 }))
+        }
+        (Some("remote"), Some(addr)) => Ok(Box::new(Remote::connect(addr))),
+        _ => Err(format!("Unknown controller {:?}", spec)),
+    }
+}
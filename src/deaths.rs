@@ -0,0 +1,199 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+use serde_derive::{Deserialize, Serialize};
+use ytesrev::image::PngImage;
+
+use crate::termination::Termination;
+
+const HEADER: &str = "generation,car,driver,species,time,x,y,reason";
+
+/// Where and why a car stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeathEvent {
+    pub car: usize,
+    pub driver: &'static str,   // `Controller::kind`
+    pub species: Option<usize>, // None for cars that aren't evolved
+    pub time: f64,
+    pub pos: (f64, f64),
+    pub reason: Termination,
+}
+
+/// Every car that stopped during one episode, in order
+#[derive(Debug, Clone, Default)]
+pub struct DeathLog {
+    pub events: Vec<DeathEvent>,
+}
+
+impl DeathLog {
+    pub fn append_to(&self, path: &str, generation: usize) -> io::Result<()> {
+        let is_new = File::open(path).is_err();
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        if is_new {
+            writeln!(f, "{}", HEADER)?;
+        }
+
+        for event in &self.events {
+            writeln!(
+                f,
+                "{},{},{},{},{:.2},{:.1},{:.1},{}",
+                generation,
+                event.car,
+                event.driver,
+                event.species.map(|x| x.to_string()).unwrap_or_default(),
+                event.time,
+                event.pos.0,
+                event.pos.1,
+                event.reason.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Crash positions counted in square cells, over all generations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heatmap {
+    pub map: String,
+    pub width: usize, // In cells
+    pub height: usize,
+    pub cell: usize, // Side of a cell, in pixels
+    pub counts: Vec<u32>,
+}
+
+impl Heatmap {
+    pub fn new(map: &str, map_width: usize, map_height: usize, cell: usize) -> Heatmap {
+        let (width, height) = (map_width.div_ceil(cell), map_height.div_ceil(cell));
+        Heatmap {
+            map: map.to_string(),
+            width,
+            height,
+            cell,
+            counts: vec![0; width * height],
+        }
+    }
+
+    pub fn add(&mut self, pos: (f64, f64)) {
+        let (x, y) = (pos.0 / self.cell as f64, pos.1 / self.cell as f64);
+        if x >= 0. && y >= 0. && (x as usize) < self.width && (y as usize) < self.height {
+            self.counts[x as usize + y as usize * self.width] += 1;
+        }
+    }
+
+    /// A transparent image of `width` by `height` pixels, more opaque red where more cars crashed.
+    /// Counts are blurred over the neighbouring cells so single crashes show up too.
+    pub fn to_image(&self, width: usize, height: usize) -> PngImage {
+        let mut smooth = vec![0.; self.counts.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut sum = 0.;
+                for dy in -1..=1isize {
+                    for dx in -1..=1isize {
+                        let (nx, ny) = (x as isize + dx, y as isize + dy);
+                        if nx >= 0
+                            && ny >= 0
+                            && (nx as usize) < self.width
+                            && (ny as usize) < self.height
+                        {
+                            let weight = if dx == 0 && dy == 0 { 1. } else { 0.5 };
+                            sum +=
+                                weight * self.counts[nx as usize + ny as usize * self.width] as f64;
+                        }
+                    }
+                }
+                smooth[x + y * self.width] = sum;
+            }
+        }
+        let max = smooth.iter().cloned().fold(1., f64::max);
+
+        let mut data = vec![0; 4 * width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let (cx, cy) = (x / self.cell, y / self.cell);
+                if cx >= self.width || cy >= self.height {
+                    continue;
+                }
+                let heat = smooth[cx + cy * self.width] / max;
+                let i = 4 * (x + y * width);
+                data[i] = 255;
+                data[i + 1] = ((1. - heat) * 200.) as u8;
+                data[i + 3] = (heat.sqrt() * 200.) as u8;
+            }
+        }
+
+        PngImage {
+            data,
+            width,
+            height,
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn test_deaths() {
+    let mut heatmap = Heatmap::new("map.png", 100, 50, 8);
+    assert_eq!((heatmap.width, heatmap.height), (13, 7));
+    for _ in 0..3 {
+        heatmap.add((20., 20.));
+    }
+    heatmap.add((99., 49.));
+    heatmap.add((-1., 20.));
+    heatmap.add((110., 20.));
+    assert_eq!(heatmap.counts.iter().sum::<u32>(), 4);
+
+    let im = heatmap.to_image(100, 50);
+    let alpha = |x: usize, y: usize| im.data[4 * (x + y * 100) + 3];
+    assert_eq!(alpha(20, 20), 200);
+    assert!(alpha(30, 20) > 0 && alpha(30, 20) < alpha(20, 20));
+    assert_eq!(alpha(60, 20), 0);
+    assert!(alpha(99, 49) > 0);
+
+    let path = std::env::temp_dir().join("neat-driver-test-deaths.csv");
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+    let log = DeathLog {
+        events: vec![
+            DeathEvent {
+                car: 3,
+                driver: "human",
+                species: None,
+                time: 1.5,
+                pos: (20., 30.),
+                reason: Termination::OutOfBounds,
+            },
+            DeathEvent {
+                car: 4,
+                driver: "neat",
+                species: Some(2),
+                time: 2.,
+                pos: (40., 10.),
+                reason: Termination::Crashed,
+            },
+            DeathEvent {
+                car: 5,
+                driver: "genome",
+                species: None,
+                time: 3.,
+                pos: (50., 10.),
+                reason: Termination::Timeout,
+            },
+        ],
+    };
+    log.append_to(path, 0).unwrap();
+    log.append_to(path, 1).unwrap();
+    let text = std::fs::read_to_string(path).unwrap();
+    assert_eq!(
+        text.lines().collect::<Vec<_>>(),
+        vec![
+            HEADER,
+            "0,3,human,,1.50,20.0,30.0,out of bounds",
+            "0,4,neat,2,2.00,40.0,10.0,wall",
+            "0,5,genome,,3.00,50.0,10.0,timeout",
+            "1,3,human,,1.50,20.0,30.0,out of bounds",
+            "1,4,neat,2,2.00,40.0,10.0,wall",
+            "1,5,genome,,3.00,50.0,10.0,timeout"
+        ]
+    );
+    std::fs::remove_file(path).unwrap();
+    println!("test_deaths: ok");
+}
@@ -0,0 +1,112 @@
+use crate::termination::Termination;
+
+/// What happened during one car's episode so far
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EpisodeSummary {
+    pub progress: f64,                    // Pixels along the track, counting full laps
+    pub lap_length: f64, // The length of a lap, or of the whole track without a finish line
+    pub time: f64,       // In seconds
+    pub laps: i32,       // Negative after crossing the finish line backwards
+    pub collisions: u32, // Times the car hit something
+    pub distance: f64,   // Pixels driven, in any direction
+    pub mean_speed: f64, // Pixels per second
+    pub clearance: f64,  // Mean distance to the nearest wall
+    pub termination: Option<Termination>, // Why the episode ended, if it has
+}
+
+/// Scores an episode. The best score a car reaches during its episode is its fitness.
+pub trait FitnessFn {
+    fn name(&self) -> &str;
+    fn fitness(&self, summary: &EpisodeSummary) -> f64;
+}
+
+/// Progress over time, plus ten seconds. What fitness has always been.
+pub struct Progress;
+
+impl FitnessFn for Progress {
+    fn name(&self) -> &str {
+        "progress"
+    }
+
+    fn fitness(&self, summary: &EpisodeSummary) -> f64 {
+        summary.progress / (summary.time + 10.)
+    }
+}
+
+/// Laps completed plus the fraction of the current one
+pub struct Laps;
+
+impl FitnessFn for Laps {
+    fn name(&self) -> &str {
+        "laps"
+    }
+
+    fn fitness(&self, summary: &EpisodeSummary) -> f64 {
+        summary.progress / summary.lap_length
+    }
+}
+
+/// Progress, rewarding keeping away from the walls
+pub struct Centreline {
+    pub weight: f64, // Per pixel of mean clearance
+}
+
+impl FitnessFn for Centreline {
+    fn name(&self) -> &str {
+        "centreline"
+    }
+
+    fn fitness(&self, summary: &EpisodeSummary) -> f64 {
+        Progress.fitness(summary) + self.weight * summary.clearance
+    }
+}
+
+/// Progress over time, scaled by how directly the car drove. Punishes weaving and going back and
+/// forth.
+pub struct Efficiency;
+
+impl FitnessFn for Efficiency {
+    fn name(&self) -> &str {
+        "efficiency"
+    }
+
+    fn fitness(&self, summary: &EpisodeSummary) -> f64 {
+        let directness = (summary.progress / summary.distance.max(1.)).min(1.);
+        Progress.fitness(summary) * directness
+    }
+}
+
+/// Fitness functions by name
+pub struct FitnessRegistry {
+    functions: Vec<Box<dyn FitnessFn + Sync>>,
+}
+
+impl FitnessRegistry {
+    pub fn with_builtins() -> FitnessRegistry {
+        let mut registry = FitnessRegistry {
+            functions: Vec::new(),
+        };
+        registry.register(Box::new(Progress));
+        registry.register(Box::new(Laps));
+        registry.register(Box::new(Centreline { weight: 0.1 }));
+        registry.register(Box::new(Efficiency));
+        registry
+    }
+
+    /// Adds a function, replacing any with the same name
+    pub fn register(&mut self, function: Box<dyn FitnessFn + Sync>) {
+        self.functions.retain(|x| x.name() != function.name());
+        self.functions.push(function);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&(dyn FitnessFn + Sync)> {
+        self.functions
+            .iter()
+            .find(|x| x.name() == name)
+            .map(|x| &**x)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.functions.iter().map(|x| x.name()).collect()
+    }
+}
@@ -8,118 +8,326 @@ use ytesrev::prelude::*;
 use ytesrev::utils::line_aa;
 
 use crate::car_textures::*;
+use crate::control::Action;
+use crate::controller::{Controller, Human};
+use crate::fitness::{EpisodeSummary, FitnessFn};
 use crate::map::{Map, Tile};
-use crate::neat::Genome;
-use crate::NUM_INPUTS;
+use crate::sensors::SensorSuite;
+use crate::termination::{Termination, TerminationRules, Watchdog};
+use crate::vehicle::{Body, Vehicle, VehicleState};
+use crate::{CAR_SIZE, MAX_LAPS, SECTORS, TERMINATION, VEHICLE};
+
+const MAX_SWEEP_STEP: f64 = 0.5; // Largest move of any point of the car between wall checks
 
 pub struct Game<'a> {
     pub map: &'a Map,
-    pub player_pos: (f64, f64),
-    pub player_dir: f64, // In radians, 0 = right
-    pub player_speed: f64,
+    pub sensors: &'a SensorSuite,
+    pub car: VehicleState,
+    pub prev_car: VehicleState,      // Before the last step
+    pub interpolation: f64,          // How far between `prev_car` and `car` to draw the car
+    pub trail: Vec<(f32, f32, f32)>, // Position and direction after every step, from the start
+    pub vehicle: Vehicle,
+    pub action: Action,
+    pub footprint: (f64, f64), // Length and width
     pub died: bool,
-    pub best_score: f64,
+    pub solid: bool, // Whether other cars collide with it, once it's got clear of them
+    pub best_score: f64, // The best fitness reached so far
 
-    pub controller: Controller,
+    pub controller: Box<dyn Controller + Send>, // Games are drawn from ytesrev's drawing thread
     pub time: f64,
+    pub clearance_time: f64, // Clearance integrated over time
+    pub distance: f64,
+    pub collisions: u32,
 
     pub improved: bool,
-}
 
-pub enum Controller {
-    NEAT(Genome, usize),
-    Human,
+    pub progress: u64, // Distance from the start, or from the finish line, of the current tile
+    pub laps: i32,     // Negative after crossing the finish line backwards
+    pub lap_times: Vec<f64>,
+    pub sector_times: Vec<f64>, // Since the start of the current lap
+    pub lap_start: f64,
+    pub finished: bool,
+    pub termination: Option<Termination>,
+    watchdog: Watchdog,
+    line_entry: Option<(f64, f64)>, // Where the car got onto the finish line
 }
 
 impl<'a> Game<'a> {
-    pub fn new_human(map: &'a Map) -> Game<'a> {
+    pub fn new(
+        map: &'a Map,
+        sensors: &'a SensorSuite,
+        controller: Box<dyn Controller + Send>,
+    ) -> Game<'a> {
         Game {
             map,
-            player_pos: (map.start.0 as f64, map.start.1 as f64),
-            player_dir: 0.,
-            player_speed: 0.,
+            sensors,
+            car: VehicleState::at((map.start.0 as f64, map.start.1 as f64), 0.),
+            prev_car: VehicleState::at((map.start.0 as f64, map.start.1 as f64), 0.),
+            interpolation: 1.,
+            trail: vec![(map.start.0 as f32, map.start.1 as f32, 0.)],
+            vehicle: VEHICLE,
+            action: Action::default(),
+            // The player's texture is drawn larger to stand out, but it's the same car
+            footprint: CAR_SIZE
+                .unwrap_or((CAR_TEXTURE_AI.width as f64, CAR_TEXTURE_AI.height as f64)),
             died: false,
+            solid: false,
             best_score: 0.,
             time: 0.,
-            controller: Controller::Human,
+            clearance_time: 0.,
+            distance: 0.,
+            collisions: 0,
+            controller,
             improved: false,
+            progress: 0,
+            laps: 0,
+            lap_times: Vec::new(),
+            sector_times: Vec::new(),
+            lap_start: 0.,
+            finished: false,
+            termination: None,
+            watchdog: Watchdog::default(),
+            line_entry: None,
         }
     }
 
-    pub fn cast_ray(&self, from: (f64, f64), angle: f64) -> (f64, f64) {
-        let mut at = from;
-        loop {
-            match self
-                .map
-                .data
-                .get(at.0 as usize + at.1 as usize * self.map.width)
-            {
-                Some(Tile::Wall) | None => {
-                    return at;
+    // Checks the car's footprint along the way from `from` to the current state, stopping the
+    // car where it first touches a wall. Returns whether it did.
+    fn sweep_collision(&mut self, from: VehicleState) -> bool {
+        let to = self.car;
+        let dist = ((to.pos.0 - from.pos.0).powi(2) + (to.pos.1 - from.pos.1).powi(2)).sqrt();
+        let radius = (self.footprint.0.powi(2) + self.footprint.1.powi(2)).sqrt() / 2.;
+        let turn = (to.dir - from.dir).abs() * radius;
+        let steps = ((dist + turn) / MAX_SWEEP_STEP).ceil().max(1.) as usize;
+
+        for i in 1..=steps {
+            let t = i as f64 / steps as f64;
+            let pos = (
+                from.pos.0 + (to.pos.0 - from.pos.0) * t,
+                from.pos.1 + (to.pos.1 - from.pos.1) * t,
+            );
+            let dir = from.dir + (to.dir - from.dir) * t;
+
+            if self.map.footprint_hits_wall(pos, dir, self.footprint) {
+                self.car.pos = pos;
+                self.car.dir = dir;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Updates `best_score` with the fitness of the episode so far
+    pub fn score(&mut self, fitness: &dyn FitnessFn) {
+        let score = fitness.fitness(&self.summary());
+        if score > self.best_score {
+            self.best_score = score;
+            self.improved = true;
+        }
+    }
+
+    /// Where the car is drawn, between the last two steps
+    pub fn render_state(&self) -> VehicleState {
+        let (a, b, t) = (&self.prev_car, &self.car, self.interpolation);
+        VehicleState {
+            pos: (
+                a.pos.0 + (b.pos.0 - a.pos.0) * t,
+                a.pos.1 + (b.pos.1 - a.pos.1) * t,
+            ),
+            dir: a.dir + (b.dir - a.dir) * t,
+            ..*b
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.termination.is_none()
+    }
+
+    /// Stops the car, unless it already has been
+    pub fn stop(&mut self, reason: Termination) {
+        if self.termination.is_none() {
+            self.termination = Some(reason);
+            self.died = reason.crashed();
+            self.finished = reason == Termination::Finished;
+        }
+    }
+
+    /// Progress in pixels, counting full laps on tracks with a finish line
+    pub fn total_progress(&self) -> f64 {
+        match &self.map.finish {
+            Some(finish) => self.laps as f64 * finish.lap_length as f64 + self.progress as f64,
+            None => self.progress as f64,
+        }
+    }
+
+    pub fn summary(&self) -> EpisodeSummary {
+        let lap_length = match &self.map.finish {
+            Some(finish) => finish.lap_length,
+            None => self.map.end_distance(),
+        };
+        let time = self.time.max(1e-9);
+
+        EpisodeSummary {
+            progress: self.total_progress(),
+            lap_length: lap_length as f64,
+            time: self.time,
+            laps: self.laps,
+            collisions: self.collisions,
+            distance: self.distance,
+            mean_speed: self.distance / time,
+            clearance: self.clearance_time / time,
+            termination: self.termination,
+        }
+    }
+
+    // Follows the car from `from` to where it is now, counting it crossing the finish line
+    fn track_laps(&mut self, from: (f64, f64)) {
+        let finish = match &self.map.finish {
+            Some(finish) => finish,
+            None => return,
+        };
+
+        let to = self.car.pos;
+        let dist = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+        let steps = (dist * 2.).ceil() as usize; // Every half pixel
+
+        let mut last = from;
+        for i in 1..=steps {
+            let t = i as f64 / steps as f64;
+            let at = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+
+            match (self.map.on_finish(at), self.line_entry) {
+                (true, None) => self.line_entry = Some(last),
+                (false, Some(entry)) => {
+                    self.line_entry = None;
+                    let (sin, cos) = finish.direction.sin_cos();
+                    if (at.0 - entry.0) * cos + (at.1 - entry.1) * sin > 0. {
+                        self.laps += 1;
+                    } else {
+                        self.laps -= 1;
+                    }
+
+                    // Only laps further than the car has been before count
+                    if self.laps > self.lap_times.len() as i32 {
+                        self.lap_times.push(self.time - self.lap_start);
+                        self.lap_start = self.time;
+                        if MAX_LAPS.is_some_and(|max| self.laps >= max) {
+                            self.stop(Termination::Finished);
+                        }
+                    }
+                    self.sector_times.clear();
                 }
                 _ => {}
             }
+            last = at;
+        }
+    }
+
+    fn track_sectors(&mut self) {
+        let lap_length = match &self.map.finish {
+            Some(finish) => finish.lap_length,
+            None => return,
+        };
 
-            at.0 += angle.cos();
-            at.1 += angle.sin();
+        let sector = self.sector_times.len() as u64 + 1;
+        if sector < SECTORS as u64 && self.progress >= sector * lap_length / SECTORS as u64 {
+            self.sector_times.push(self.time - self.lap_start);
         }
     }
-}
 
-impl Drawable for Game<'_> {
-    fn update(&mut self, dt: f64) {
-        if self.died {
+    // One step of physics, collisions and lap keeping
+    fn advance(&mut self, dt: f64) {
+        self.time += dt;
+        let from = self.car;
+        self.vehicle.step(&mut self.car, self.action, dt);
+
+        let collided = self.sweep_collision(from);
+        let (dx, dy) = (self.car.pos.0 - from.pos.0, self.car.pos.1 - from.pos.1);
+        self.distance += (dx * dx + dy * dy).sqrt();
+        if collided {
+            self.collisions += 1;
+            let size = self.footprint;
+            if self
+                .map
+                .footprint_in_bounds(self.car.pos, self.car.dir, size)
+            {
+                self.stop(Termination::Crashed);
+            } else {
+                self.stop(Termination::OutOfBounds);
+            }
             return;
         }
-        self.time += dt;
-        self.player_pos.0 += self.player_speed * self.player_dir.cos() * dt;
-        self.player_pos.1 += self.player_speed * self.player_dir.sin() * dt;
 
-        if self.player_pos.0 < 0.
-            || self.player_pos.1 < 0.
-            || self.player_pos.0 > self.map.width as f64
-            || self.player_pos.1 > self.map.get_height() as f64
+        if self.car.pos.0 < 0.
+            || self.car.pos.1 < 0.
+            || self.car.pos.0 > self.map.width as f64
+            || self.car.pos.1 > self.map.get_height() as f64
         {
-            self.died = true;
+            self.stop(Termination::OutOfBounds);
         }
 
         match self
             .map
             .data
-            .get(self.player_pos.0 as usize + self.player_pos.1 as usize * self.map.width)
+            .get(self.car.pos.0 as usize + self.car.pos.1 as usize * self.map.width)
         {
             Some(Tile::Wall) | None => {
-                self.died = true;
+                self.stop(Termination::Crashed);
             }
             Some(Tile::Ground(x)) => {
-                let score = *x as f64 / (self.time + 10.);
-                if score > self.best_score {
-                    self.best_score = score;
-                    self.improved = true;
-                }
+                self.clearance_time += self.map.clearance(self.car.pos) * dt;
+                self.progress = *x;
+                self.track_laps(from.pos);
+                self.track_sectors();
             }
             _ => {}
         }
 
-        if let Controller::NEAT(genome, _) = &self.controller {
-            let mut inputs: [f64; NUM_INPUTS] = [0.; NUM_INPUTS];
+        if self.running() {
+            let turned = self.car.dir - from.dir;
+            let progress = self.total_progress();
+            // Someone playing can stop to look or back out of a corner, only the time limit applies
+            let rules = if self.controller.is_human() {
+                TerminationRules {
+                    stagnation: None,
+                    backwards: None,
+                    spinning: None,
+                    ..TERMINATION
+                }
+            } else {
+                TERMINATION
+            };
+            if let Some(reason) = self.watchdog.check(&rules, self.time, dt, progress, turned) {
+                self.stop(reason);
+            }
+        }
+    }
 
-            for i in 1..NUM_INPUTS {
-                let d_angle = (i as f64 / (NUM_INPUTS - 1) as f64 - 0.5) * PI;
-                let angle = self.player_dir + d_angle;
-                let ray = self.cast_ray(self.player_pos, angle);
+    /// What the car senses, with `others` being the cars it can see
+    pub fn sense(&self, others: &[Body]) -> Vec<f64> {
+        self.sensors.sense(self.map, &self.car, others)
+    }
 
-                let dx = ray.0 - self.player_pos.0;
-                let dy = ray.1 - self.player_pos.1;
-                let dist = (dx * dx + dy * dy).sqrt();
-                inputs[i] = dist;
-            }
-            inputs[0] = self.player_speed;
+    pub fn body(&self) -> Body {
+        Body {
+            state: self.car,
+            size: self.footprint,
+        }
+    }
+}
 
-            let res = genome.evaluate(&inputs);
-            self.player_speed += (res[0].atanh().max(-40.).min(40.)) * dt;
-            self.player_dir += res[1] * dt * 10.;
+impl Drawable for Game<'_> {
+    fn update(&mut self, dt: f64) {
+        self.prev_car = self.car;
+        if !self.running() {
+            return;
         }
+        self.advance(dt);
+        self.trail.push((
+            self.car.pos.0 as f32,
+            self.car.pos.1 as f32,
+            self.car.dir as f32,
+        ));
     }
 
     fn step(&mut self) {}
@@ -137,9 +345,10 @@ impl Drawable for Game<'_> {
 
     fn draw(&self, canvas: &mut Canvas<Window>, position: &Position, settings: DrawSettings) {
         let r = position.into_rect_with_size(self.map.width as u32, self.map.get_height() as u32);
+        let car = self.render_state();
 
-        match self.controller {
-            Controller::NEAT(_, s) => {
+        match self.controller.species() {
+            Some(s) => {
                 load_species(s);
 
                 if let Ok(ref mut textures) = SPECIES_TEXTURES.lock() {
@@ -151,20 +360,17 @@ impl Drawable for Game<'_> {
                     }
                 }
             }
-            Controller::Human => self.draw_texture(canvas, position, &*CAR_TEXTURE_PLAYER),
+            None if self.controller.is_human() => {
+                self.draw_texture(canvas, position, &CAR_TEXTURE_PLAYER)
+            }
+            None => self.draw_texture(canvas, position, &CAR_TEXTURE_AI),
         }
 
-        for i in 0..NUM_INPUTS - 1 {
-            let d_angle = (i as f64 / (NUM_INPUTS - 2) as f64 - 0.5) * PI;
-            let angle = self.player_dir + d_angle;
-            let ray = self.cast_ray(self.player_pos, angle);
-
+        for hit in self.sensors.cast_rays(self.map, &car) {
+            let ray = hit.point;
             line_aa(
                 canvas,
-                (
-                    self.player_pos.0 + r.x() as f64,
-                    self.player_pos.1 + r.y() as f64,
-                ),
+                (car.pos.0 + r.x() as f64, car.pos.1 + r.y() as f64),
                 (ray.0 + r.x() as f64, ray.1 + r.y() as f64),
             );
         }
@@ -179,38 +385,110 @@ impl Game<'_> {
         car_texture: &PngImage,
     ) {
         let r = position.into_rect_with_size(self.map.width as u32, self.map.get_height() as u32);
+        draw_car(canvas, r, &self.render_state(), car_texture);
+    }
+}
+
+/// Draws a car texture centered on `car`, on a map drawn at `r`
+pub fn draw_car(canvas: &mut Canvas<Window>, r: Rect, car: &VehicleState, car_texture: &PngImage) {
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(
+            Some(PixelFormatEnum::ABGR8888),
+            car_texture.width as u32,
+            car_texture.height as u32,
+        )
+        .expect("Can't make texture");
+
+    texture.set_blend_mode(BlendMode::Blend);
+    texture
+        .update(None, car_texture.data.as_slice(), 4 * car_texture.width)
+        .expect("Can't update texture");
 
-        // Draw car
-        let creator = canvas.texture_creator();
-        let mut texture = creator
-            .create_texture_target(
-                Some(PixelFormatEnum::ABGR8888),
+    let at = Point::new(car.pos.0 as i32 + r.x(), car.pos.1 as i32 + r.y());
+    canvas
+        .copy_ex(
+            &texture,
+            None,
+            Some(Rect::from_center(
+                at,
                 car_texture.width as u32,
                 car_texture.height as u32,
-            )
-            .expect("Can't make texture");
-
-        texture.set_blend_mode(BlendMode::Blend);
-        texture.update(None, car_texture.data.as_slice(), 4 * car_texture.width);
-
-        let at = Point::new(
-            self.player_pos.0 as i32 + r.x(),
-            self.player_pos.1 as i32 + r.y(),
-        );
-        canvas
-            .copy_ex(
-                &texture,
-                None,
-                Some(Rect::from_center(
-                    at,
-                    car_texture.width as u32,
-                    car_texture.height as u32,
-                )),
-                self.player_dir / PI * 180.,
-                None,
-                false,
-                false,
-            )
-            .expect("Can't make texture");
+            )),
+            car.dir / PI * 180.,
+            None,
+            false,
+            false,
+        )
+        .expect("Can't make texture");
+}
+
+#[allow(unused)]
+pub fn test_laps() {
+    // A ring, with the finish line across the top and the start just right of it
+    let size = 200;
+    let mut data = vec![0; 4 * size * size];
+    for y in 0..size {
+        for x in 0..size {
+            let r = ((x as f64 - 100.).powi(2) + (y as f64 - 100.).powi(2)).sqrt();
+            let col = if x == 100 && (10..50).contains(&y) {
+                [0, 255, 0]
+            } else if (x, y) == (105, 30) {
+                [255, 0, 0]
+            } else if r > 50. && r < 90. {
+                [255, 255, 255]
+            } else {
+                [0, 0, 0]
+            };
+            let i = 4 * (x + y * size);
+            data[i..i + 3].copy_from_slice(&col);
+            data[i + 3] = 255;
+        }
     }
+    let map = Map::create_from_image(&PngImage {
+        data,
+        width: size,
+        height: size,
+    });
+    let sensors = SensorSuite::default();
+    let mut game = Game::new(&map, &sensors, Box::new(Human));
+
+    let finish = map.finish.as_ref().expect("No finish line");
+    assert!(finish.direction.cos() > 0.99); // Clockwise, so right at the top
+    assert!(finish.lap_length > 300 && finish.lap_length < 500);
+
+    // Drives along the middle of the ring, one degree at a time
+    let mut angle = -PI / 2. + 0.1;
+    let mut drive = |game: &mut Game, degrees: i32| {
+        for _ in 0..degrees.abs() {
+            angle += (degrees.signum() as f64).to_radians();
+            let from = game.car.pos;
+            game.car.pos = (100. + 70. * angle.cos(), 100. + 70. * angle.sin());
+            game.time += 0.1;
+            if let Tile::Ground(x) =
+                map.data[game.car.pos.0 as usize + game.car.pos.1 as usize * size]
+            {
+                game.progress = x;
+            }
+            game.track_laps(from);
+            game.track_sectors();
+        }
+    };
+
+    drive(&mut game, 180);
+    assert_eq!((game.laps, game.sector_times.len()), (0, 1));
+    drive(&mut game, 180);
+    assert_eq!((game.laps, game.lap_times.len()), (1, 1));
+    assert!(game.lap_times[0] > 35. && game.lap_times[0] < 36.);
+
+    // Backing over the line and crossing it again doesn't count as another lap
+    drive(&mut game, -10);
+    assert_eq!((game.laps, game.lap_times.len()), (0, 1));
+    drive(&mut game, 10);
+    assert_eq!((game.laps, game.lap_times.len()), (1, 1));
+
+    drive(&mut game, 720);
+    assert_eq!((game.laps, game.lap_times.len()), (3, 3));
+    assert!(game.finished);
+    println!("test_laps: ok");
 }
@@ -0,0 +1,75 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::game::Game;
+use crate::vehicle::VehicleState;
+
+/// A run to race against, drawn where the car was at the same time into its episode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ghost {
+    pub map: String,
+    pub fitness: f64,
+    pub dt: f64,
+    pub trail: Vec<(f32, f32, f32)>, // Like `Game::trail`
+}
+
+impl Ghost {
+    /// Where the ghost is `time` seconds in, staying where it stopped at the end
+    pub fn state_at(&self, time: f64) -> Option<VehicleState> {
+        let last = self.trail.len().checked_sub(1)?;
+        let t = (time / self.dt).max(0.);
+        let i = (t.floor() as usize).min(last);
+        let (a, b) = (self.trail[i], self.trail[(i + 1).min(last)]);
+        let f = (t - i as f64).min(1.) as f32;
+
+        Some(VehicleState::at(
+            (
+                (a.0 + (b.0 - a.0) * f) as f64,
+                (a.1 + (b.1 - a.1) * f) as f64,
+            ),
+            (a.2 + (b.2 - a.2) * f) as f64,
+        ))
+    }
+}
+
+/// The best network and human runs so far, saved with the population
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ghosts {
+    pub best: Option<Ghost>,
+    pub human: Option<Ghost>,
+}
+
+impl Ghosts {
+    /// Keeps the run of `game` if it beats the ghost it would replace. Ghosts from other maps are
+    /// always replaced. Only networks being evolved and humans leave ghosts.
+    pub fn offer(&mut self, game: &Game, map: &str, dt: f64) {
+        let slot = if game.controller.genome().is_some() {
+            &mut self.best
+        } else if game.controller.is_human() {
+            &mut self.human
+        } else {
+            return;
+        };
+
+        let better = match slot {
+            Some(ghost) => ghost.map != map || game.best_score > ghost.fitness,
+            None => true,
+        };
+        if better {
+            *slot = Some(Ghost {
+                map: map.to_string(),
+                fitness: game.best_score,
+                dt,
+                trail: game.trail.clone(),
+            });
+        }
+    }
+
+    /// The ghosts recorded on `map`, with whether they're the human's
+    pub fn on_map<'a>(&'a self, map: &'a str) -> impl Iterator<Item = (&'a Ghost, bool)> + 'a {
+        self.best
+            .iter()
+            .map(|x| (x, false))
+            .chain(self.human.iter().map(|x| (x, true)))
+            .filter(move |(x, _)| x.map == map)
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+use bincode::{deserialize_from, serialize_into};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::control::{Action, ActionDecoder};
+use crate::neat::Genome;
+use crate::network::Network;
+use crate::preprocess::Preprocessor;
+
+/// One step of human driving
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub inputs: Vec<f32>, // Raw sensor values, before preprocessing
+    pub action: Action,
+}
+
+/// Recorded human driving, over all sessions. Only the latest `max_samples` are kept.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Demonstrations {
+    pub samples: Vec<Sample>,
+    pub max_samples: usize,
+}
+
+impl Demonstrations {
+    pub fn new(max_samples: usize) -> Demonstrations {
+        Demonstrations {
+            samples: Vec::new(),
+            max_samples,
+        }
+    }
+
+    pub fn record(&mut self, inputs: &[f64], action: Action) {
+        self.samples.push(Sample {
+            inputs: inputs.iter().map(|&x| x as f32).collect(),
+            action,
+        });
+        if self.samples.len() > self.max_samples {
+            let extra = self.samples.len() - self.max_samples;
+            self.samples.drain(..extra);
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        serialize_into(BufWriter::new(File::create(path)?), self).map_err(io::Error::other)
+    }
+
+    pub fn load(path: &str) -> io::Result<Demonstrations> {
+        deserialize_from(File::open(path)?).map_err(io::Error::other)
+    }
+
+    /// How much the genome drives like the human, from 0 to 1. Compares the actions it would have
+    /// taken on every sample with the closest the decoder can get to the human's, so a decoder
+    /// without a brake isn't held to braking. None without samples, or if they were sensed
+    /// differently.
+    pub fn score(
+        &self,
+        genome: &Genome,
+        preprocessor: &Preprocessor,
+        decoder: ActionDecoder,
+    ) -> Option<f64> {
+        let nr_inputs = preprocessor.channels.len();
+        if self.samples.is_empty() || self.samples.iter().any(|x| x.inputs.len() != nr_inputs) {
+            return None;
+        }
+
+        let network = Network::compile(genome);
+
+        let mut error = 0.;
+        for sample in &self.samples {
+            let mut inputs = sample.inputs.iter().map(|&x| x as f64).collect::<Vec<_>>();
+            // The demonstrations shouldn't move running statistics
+            preprocessor.process_without_learning(&mut inputs);
+            let action = decoder.decode(&network.evaluate(&inputs));
+            let target = decoder.closest(sample.action);
+
+            error += (action.throttle - target.throttle).powi(2)
+                + (action.brake - target.brake).powi(2)
+                + (action.steer - target.steer).powi(2);
+        }
+        Some(1. / (1. + error / self.samples.len() as f64))
+    }
+}
+
+#[allow(unused)]
+pub fn test_imitation() {
+    use crate::preprocess::Channel;
+
+    let preprocessor = Preprocessor {
+        channels: vec![
+            Channel {
+                min: 0.,
+                max: 100.,
+                ..Channel::default()
+            };
+            4
+        ],
+        learn: true,
+    };
+    let decoder = ActionDecoder::Continuous;
+    let (genome, _) = Genome::init(4, decoder.nr_outputs());
+    let network = Network::compile(&genome);
+
+    // Demonstrations of exactly what the genome does
+    let mut demos = Demonstrations::new(50);
+    for i in 0..100 {
+        let raw = (0..4)
+            .map(|j| ((i * 7 + j * 13) % 100) as f64)
+            .collect::<Vec<_>>();
+        let mut inputs = raw.clone();
+        preprocessor.process_without_learning(&mut inputs);
+        demos.record(&raw, decoder.decode(&network.evaluate(&inputs)));
+    }
+    assert_eq!(demos.samples.len(), 50);
+    let score = demos.score(&genome, &preprocessor, decoder).unwrap();
+    assert!((score - 1.).abs() < 1e-6);
+
+    // Steering the other way is worse
+    let mut opposite = demos.clone();
+    for sample in &mut opposite.samples {
+        sample.action.steer = -sample.action.steer.signum();
+    }
+    assert!(opposite.score(&genome, &preprocessor, decoder).unwrap() < score);
+
+    // Braking can't be held against a decoder that can't brake
+    let braking = Demonstrations {
+        samples: demos
+            .samples
+            .iter()
+            .map(|x| Sample {
+                action: Action {
+                    brake: 1.,
+                    ..x.action
+                },
+                ..x.clone()
+            })
+            .collect(),
+        ..demos.clone()
+    };
+    let braking_score = braking.score(&genome, &preprocessor, decoder).unwrap();
+    assert!((braking_score - 1.).abs() < 1e-6);
+    assert!(Demonstrations::new(10)
+        .score(&genome, &preprocessor, decoder)
+        .is_none());
+
+    let path = std::env::temp_dir().join("neat-driver-test-demos.bc");
+    let path = path.to_str().unwrap();
+    demos.save(path).expect("Can't save demonstrations");
+    let loaded = Demonstrations::load(path).expect("Can't load demonstrations");
+    assert_eq!(loaded.samples.len(), 50);
+    assert_eq!(loaded.samples[0].action, demos.samples[0].action);
+    std::fs::remove_file(path).unwrap();
+    println!("test_imitation: ok");
+}
@@ -1,8 +1,10 @@
 #![feature(bind_by_move_pattern_guards)]
 
 use std::cell::Cell;
+use std::collections::HashMap;
 use std::fs::File;
 use std::mem::replace;
+use std::time::Instant;
 use ytesrev::prelude::*;
 use ytesrev::window::WSETTINGS_MAIN;
 
@@ -10,73 +12,469 @@ use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::mouse::MouseUtil;
 
-use bincode::{deserialize_from, serialize_into};
+use bincode::{deserialize, serialize_into};
+use serde_derive::{Deserialize, Serialize};
 
 mod car_textures;
+mod clock;
+mod control;
+mod controller;
+mod deaths;
+mod fitness;
 mod game;
+mod ghost;
+mod imitation;
 mod map;
 mod neat;
-
+mod network;
+mod phylogeny;
+mod preprocess;
+mod replay;
+mod report;
+mod sensors;
+mod stats;
+mod termination;
+mod vehicle;
+
+use crate::car_textures::{CAR_TEXTURE_GHOST, CAR_TEXTURE_GHOST_PLAYER};
+use crate::clock::SimClock;
+use crate::control::{ActionDecoder, HumanInput};
+use crate::controller::{Controller, Neat, Observation};
+use crate::deaths::{DeathEvent, DeathLog, Heatmap};
+use crate::fitness::{FitnessFn, FitnessRegistry};
 use crate::game::*;
+use crate::ghost::Ghosts;
+use crate::imitation::Demonstrations;
 use crate::map::*;
 use crate::neat::*;
+use crate::network::{BatchEvaluator, Network};
+use crate::phylogeny::Phylogeny;
+use crate::preprocess::{Channel, Preprocessor, RunningStats, Scale};
+use crate::replay::{Replay, ReplayScene};
+use crate::sensors::SensorSuite;
+use crate::stats::GenerationStats;
+use crate::termination::{Termination, TerminationRules, DEFAULT_RULES};
+use crate::vehicle::{Body, Vehicle, DEFAULT_DYNAMICS};
 
 pub const POP_SIZE: usize = 100;
-pub const NUM_INPUTS: usize = 6;
 pub const SHOW: usize = 20;
 
+pub const MAP_PATH: &str = "map.png";
 pub const SAVE_PATH: &str = "save.bc";
+pub const STATS_PATH: &str = "stats.csv";
+pub const CHAMPION_PATH: &str = "champion.bc";
+pub const REPORT_PATH: &str = "report.html";
+pub const REPLAY_DIR: &str = "replays"; // One file per generation, named after it
+pub const DEATHS_PATH: &str = "deaths.csv";
+pub const PHYLOGENY_PATH: &str = "phylogeny"; // .dot, .nwk and .json are appended
+pub const DEMO_PATH: &str = "demos.bc";
+
+pub const TERMINATION: TerminationRules = DEFAULT_RULES; // When single cars are stopped early
+
+pub const CAR_COLLISIONS: bool = false; // Cars crash into each other, not just walls
+
+pub const RECORD_REPLAYS: bool = true;
+pub const REPLAYS_KEPT: usize = 10; // Generations, older replays are deleted
+pub const SHOW_HEATMAP: bool = true; // Where cars crashed, over all generations
+pub const HEATMAP_CELL: usize = 8;
+pub const SHOW_GHOSTS: bool = true; // The best run so far, and the human's
+
+// What the human car senses and does is kept, for networks to learn from. Either as a fitness
+// term, weighted per unit of similarity from 0 to 1, or with the `pretrain` subcommand.
+pub const RECORD_DEMOS: bool = true;
+pub const DEMO_MAX_SAMPLES: usize = 20_000; // The latest are kept
+pub const IMITATION_WEIGHT: f64 = 0.;
+
+pub const SIM_DT: f64 = 0.02; // Seconds of simulation per step
+pub const MAX_STEPS_PER_FRAME: usize = 500;
+
+pub const SHOW_CLEARANCE: bool = false; // Draw contour lines of the distance to the nearest wall
+
+// One of the names in `fitness_functions`. Laps only count on maps with a finish line.
+pub const FITNESS: &str = "progress";
+pub const MAX_LAPS: Option<i32> = Some(3); // Cars stop after this many laps
+pub const SECTORS: usize = 3;
+
+pub const VEHICLE: Vehicle = Vehicle::Dynamic(DEFAULT_DYNAMICS);
+pub const CAR_SIZE: Option<(f64, f64)> = None; // Length and width, taken from the textures if None
+
+pub const OFFSPRING_SCHEME: OffspringScheme = OffspringScheme::Proportional;
+
+// Only used for new populations, the decoder is saved with the population
+pub const ACTION_DECODER: ActionDecoder = ActionDecoder::Continuous;
+
+// Input preprocessing, only used for new populations. Saved populations keep their own.
+pub const INPUT_SCALE: Scale = Scale::Unit;
+pub const LOG_INPUTS: bool = false;
+pub const RUNNING_NORMALISATION: bool = false;
 
-pub const MIN_DT: f64 = 0.02;
+// Cars driven by something other than the population, see `controller::from_spec`. They're kept
+// from generation to generation, e.g. &["human", "genome:champion.bc"]. The autopilot's fitness
+// goes in the stats, to compare the population against.
+pub const EXTRA_CARS: &[&str] = &["autopilot"];
 
 static mut MOUSE: Option<MouseUtil> = None;
 
+// Bumped whenever `Save` changes. Saves from before it was versioned are migrated, other
+// versions are refused rather than read as garbage.
+const SAVE_VERSION: u32 = 6;
+
+#[derive(Serialize, Deserialize)]
+struct Save {
+    version: u32, // Has to stay first
+    species: Vec<Vec<Genome>>,
+    g_id: usize,
+    phylogeny: Phylogeny,
+    preprocessor: Preprocessor,
+    decoder: ActionDecoder,
+    sensors: SensorSuite,
+    ghosts: Ghosts,
+    heatmap: Heatmap,
+}
+
+// Only used for new populations, the sensors are saved with the population
+fn new_sensors() -> SensorSuite {
+    SensorSuite::default()
+}
+
+// Custom fitness functions are registered here
+fn fitness_functions() -> FitnessRegistry {
+    FitnessRegistry::with_builtins()
+}
+
+fn new_preprocessor(sensors: &SensorSuite) -> Preprocessor {
+    let channel = Channel {
+        log: LOG_INPUTS,
+        scale: INPUT_SCALE,
+        running: if RUNNING_NORMALISATION {
+            Some(RunningStats::default())
+        } else {
+            None
+        },
+        ..Channel::default()
+    };
+
+    Preprocessor {
+        channels: sensors.channels(&channel),
+        learn: true,
+    }
+}
+
+// A genome in the unversioned saves, which were just the population and the innovation counter
+#[derive(Deserialize)]
+struct LegacyGenome {
+    nr_ins: usize,
+    nr_outs: usize,
+    connections: HashMap<usize, Connection>,
+}
+
+// A save of a population without any history
+fn new_save(
+    mut genomes: Vec<Genome>,
+    g_id: usize,
+    decoder: ActionDecoder,
+    sensors: SensorSuite,
+    map: &Map,
+) -> Save {
+    let mut phylogeny = Phylogeny::default();
+    phylogeny.init(&mut genomes);
+
+    Save {
+        version: SAVE_VERSION,
+        species: vec![genomes],
+        g_id,
+        phylogeny,
+        preprocessor: new_preprocessor(&sensors),
+        decoder,
+        sensors,
+        ghosts: Ghosts::default(),
+        heatmap: Heatmap::new(MAP_PATH, map.width, map.get_height(), HEATMAP_CELL),
+    }
+}
+
+fn read_save(bytes: &[u8], map: &Map) -> Save {
+    if deserialize::<u32>(bytes).ok() == Some(SAVE_VERSION) {
+        return deserialize::<Save>(bytes).unwrap_or_else(|e| {
+            panic!(
+                "Can't read {} ({}), move it away to start over",
+                SAVE_PATH, e
+            )
+        });
+    }
+
+    match deserialize::<(Vec<LegacyGenome>, usize)>(bytes) {
+        Ok((genomes, g_id)) => {
+            println!("Migrating {} from before save versions", SAVE_PATH);
+            let genomes = genomes
+                .into_iter()
+                .map(|x| Genome {
+                    nr_ins: x.nr_ins,
+                    nr_outs: x.nr_outs,
+                    connections: x.connections,
+                    lineage: Lineage::default(),
+                })
+                .collect();
+            // From before the decoder and the sensors could be changed
+            new_save(
+                genomes,
+                g_id,
+                ActionDecoder::Legacy,
+                SensorSuite::default(),
+                map,
+            )
+        }
+        Err(_) => panic!(
+            "{} is from another version, expected save version {}. Move it away to start over",
+            SAVE_PATH, SAVE_VERSION
+        ),
+    }
+}
+
+// The saved population, or a new one
+fn load_save(map: &Map) -> Save {
+    match std::fs::read(SAVE_PATH) {
+        Ok(bytes) => read_save(&bytes, map),
+        Err(_) => {
+            let sensors = new_sensors();
+            let mut g_id = 0;
+            let mut genomes = Vec::with_capacity(POP_SIZE);
+            for _ in 0..POP_SIZE {
+                let (genome, new_g_id) =
+                    Genome::init(sensors.nr_inputs(), ACTION_DECODER.nr_outputs());
+                genomes.push(genome);
+                g_id = new_g_id;
+            }
+            new_save(genomes, g_id, ACTION_DECODER, sensors, map)
+        }
+    }
+}
+
+// Evolves the saved population to drive like the recorded human, without simulating anything
+fn pretrain(generations: usize) {
+    let demos = match Demonstrations::load(DEMO_PATH) {
+        Ok(demos) => demos,
+        Err(e) => {
+            println!(
+                "Can't read {} ({}), drive the human car first",
+                DEMO_PATH, e
+            );
+            return;
+        }
+    };
+    println!("{} samples", demos.samples.len());
+    if demos.samples.is_empty() {
+        return;
+    }
+
+    let img = PngImage::load_from_path(File::open(MAP_PATH).unwrap()).unwrap();
+    let mut save = load_save(&Map::create_from_image(&img));
+    let (preprocessor, decoder) = (&save.preprocessor, save.decoder);
+
+    // The whole population senses the same way, so one genome tells if the demonstrations fit
+    if demos
+        .score(&save.species[0][0], preprocessor, decoder)
+        .is_none()
+    {
+        println!(
+            "The demonstrations in {} weren't sensed like the population senses",
+            DEMO_PATH
+        );
+        return;
+    }
+
+    for generation in 0..generations {
+        let mut fitnesses = Vec::with_capacity(POP_SIZE);
+        let species = save
+            .species
+            .drain(..)
+            .map(|genomes| {
+                genomes
+                    .into_iter()
+                    .map(|genome| {
+                        fitnesses.push(demos.score(&genome, preprocessor, decoder).unwrap_or(0.));
+                        (genome, fitnesses.len() - 1)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        clean_fitnesses(&mut fitnesses);
+        println!(
+            "Pretraining generation {}: best {:.4}",
+            generation,
+            fitnesses.iter().cloned().fold(0., f64::max)
+        );
+
+        // Species are only compared by their first genome
+        let representatives = species.iter().map(|x| vec![x[0].clone()]).collect();
+        let new_population = next_generation(
+            species,
+            fitnesses,
+            &mut save.g_id,
+            &mut save.phylogeny,
+            OFFSPRING_SCHEME,
+            false,
+        );
+        save.species = class_species(new_population, representatives, &mut save.phylogeny)
+            .into_iter()
+            .map(|x| x.into_iter().map(|(genome, _)| genome).collect())
+            .collect();
+    }
+
+    let file = File::create(SAVE_PATH).unwrap();
+    serialize_into(file, &save).expect("Can't save");
+    println!("Saved");
+}
+
 fn main() {
-    let img = PngImage::load_from_path(File::open("map.png").unwrap()).unwrap();
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.get(1).map(String::as_str) {
+        Some("report") => {
+            let out = args.get(2).map(String::as_str).unwrap_or(REPORT_PATH);
+            report::write_report(STATS_PATH, CHAMPION_PATH, out).expect("Can't write report");
+            println!("Wrote {}", out);
+            return;
+        }
+        Some("replay") => {
+            let path = args
+                .get(2)
+                .expect("Usage: replay <file> [--csv <car> <out>]");
+            let replay = Replay::load(path).expect("Can't read replay");
+
+            if args.get(3).map(String::as_str) == Some("--csv") {
+                let car = args.get(4).and_then(|x| x.parse().ok()).unwrap_or(0);
+                let out = args.get(5).map(String::as_str).unwrap_or("telemetry.csv");
+                replay.export_csv(car, out).expect("Can't write telemetry");
+                println!("Wrote {}", out);
+                return;
+            }
+
+            let img = PngImage::load_from_path(File::open(&replay.map).unwrap()).unwrap();
+            let im = Map::create_from_image(&img).into_image(SHOW_CLEARANCE);
+            let mut wmng = WindowManager::init_window(
+                DrawableWrapper(ReplayScene::new(replay, im)),
+                WindowManagerSettings {
+                    windows: vec![("Replay".into(), WSETTINGS_MAIN)],
+                    ..default_settings("Replay")
+                },
+            );
+            wmng.start();
+            return;
+        }
+        Some("pretrain") => {
+            let generations = args.get(2).and_then(|x| x.parse().ok()).unwrap_or(50);
+            pretrain(generations);
+            return;
+        }
+        Some("bench") => {
+            network::bench_batch();
+            map::bench_cast_ray();
+            return;
+        }
+        Some("test") => {
+            test_simplified();
+            test_fitness_sharing();
+            test_non_finite_fitness();
+            preprocess::test_preprocess();
+            map::test_cast_ray();
+            map::test_distance_field();
+            game::test_laps();
+            clock::test_sim_clock();
+            vehicle::test_bodies();
+            termination::test_termination();
+            deaths::test_deaths();
+            replay::test_replay();
+            imitation::test_imitation();
+            return;
+        }
+        _ => {}
+    }
+
+    let img = PngImage::load_from_path(File::open(MAP_PATH).unwrap()).unwrap();
 
     let map = Map::create_from_image(&img);
-    let map_im = map.clone().into_image();
+    let map_im = map.clone().into_image(SHOW_CLEARANCE);
 
     println!("{} x {}", map_im.width, map_im.height);
 
     let mut games = Vec::with_capacity(POP_SIZE);
-    let mut g_id = 0;
-
-    if let Ok(f) = File::open(SAVE_PATH) {
-        println!("Reading save!");
-        let (genomes, g_id_): (Vec<Genome>, usize) = deserialize_from(f).expect("Can't read");
-        g_id = g_id_;
-
-        for genome in genomes {
-            games.push(Game {
-                controller: Controller::NEAT(genome, 0),
-                ..Game::new_human(&map)
-            });
-        }
-    } else {
-        for i in 0..POP_SIZE {
-            let (genome, new_g_id) = Genome::init(NUM_INPUTS, 2);
-            games.push(Game {
-                controller: Controller::NEAT(genome, 0),
-                ..Game::new_human(&map)
-            });
-            g_id = new_g_id;
+
+    let save = load_save(&map);
+
+    // Crashes on another map don't mean anything here
+    let mut heatmap = save.heatmap;
+    let fresh = Heatmap::new(MAP_PATH, map.width, map.get_height(), HEATMAP_CELL);
+    if heatmap.map != fresh.map
+        || (heatmap.width, heatmap.height, heatmap.cell) != (fresh.width, fresh.height, fresh.cell)
+    {
+        heatmap = fresh;
+    }
+
+    let sensors = save.sensors;
+    let fitness_functions = fitness_functions();
+    let fitness = fitness_functions.get(FITNESS).unwrap_or_else(|| {
+        panic!(
+            "No fitness function {:?}, there's {:?}",
+            FITNESS,
+            fitness_functions.names()
+        )
+    });
+    for (i, species) in save.species.into_iter().enumerate() {
+        for genome in species {
+            let controller = Neat {
+                genome,
+                species: i,
+                decoder: save.decoder,
+            };
+            games.push(Game::new(&map, &sensors, Box::new(controller)));
+        }
+    }
+    for spec in EXTRA_CARS {
+        match controller::from_spec(spec, sensors.nr_inputs(), save.decoder) {
+            Ok(controller) => games.push(Game::new(&map, &sensors, controller)),
+            Err(e) => println!("Can't add car {:?}: {}", spec, e),
         }
     }
 
-    // games.clear();
-    // games.push(Game::new_human(&map));
+    let generation = GenerationStats::read_all(STATS_PATH)
+        .map(|x| x.len())
+        .unwrap_or(0);
 
-    let s = DrawableWrapper(GameScene {
+    let mut scene = GameScene {
         games: games,
-        g_id,
+        g_id: save.g_id,
+        phylogeny: save.phylogeny,
+        preprocessor: save.preprocessor,
+        decoder: save.decoder,
+        generation,
+        generation_start: Instant::now(),
         map: &map,
+        sensors: &sensors,
+        fitness,
         im: map_im,
         speed_mult: 1,
         showing: None,
-        place_mouse: Cell::new(false),
+        input: HumanInput::default(),
+        capture_changed: Cell::new(false),
         last_fitness_improvment: 0.,
-    });
+        clock: SimClock::new(SIM_DT, MAX_STEPS_PER_FRAME),
+        replay: None,
+        ghosts: save.ghosts,
+        episode_time: 0.,
+        deaths: DeathLog::default(),
+        heatmap_im: heatmap.to_image(map.width, map.get_height()),
+        heatmap,
+        demos: Demonstrations::load(DEMO_PATH)
+            .map(|x| Demonstrations {
+                max_samples: DEMO_MAX_SAMPLES,
+                ..x
+            })
+            .unwrap_or_else(|_| Demonstrations::new(DEMO_MAX_SAMPLES)),
+        evaluator: BatchEvaluator::new(&[]),
+    };
+    scene.compile_networks();
+    scene.start_replay();
+
+    let s = DrawableWrapper(scene);
 
     let mut wmng = WindowManager::init_window(
         s,
@@ -97,16 +495,35 @@ struct GameScene<'a> {
     im: PngImage,
     games: Vec<Game<'a>>,
     map: &'a Map,
+    sensors: &'a SensorSuite,
+    fitness: &'a (dyn FitnessFn + Sync), // The scene is drawn from ytesrev's drawing thread
 
     g_id: usize,
+    phylogeny: Phylogeny,
+    preprocessor: Preprocessor,
+    decoder: ActionDecoder,
+
+    generation: usize,
+    generation_start: Instant,
 
     showing: Option<Vec<usize>>,
 
     speed_mult: u64,
 
-    place_mouse: Cell<bool>,
+    input: HumanInput,
+    capture_changed: Cell<bool>, // Mouse capture is applied when drawing
 
     last_fitness_improvment: f64,
+    clock: SimClock,
+    replay: Option<Replay>, // Of the current generation
+    ghosts: Ghosts,
+    episode_time: f64,
+    deaths: DeathLog,
+    heatmap: Heatmap,
+    heatmap_im: PngImage,
+    demos: Demonstrations,
+
+    evaluator: BatchEvaluator,
 }
 
 impl<'a> Drawable for GameScene<'a> {
@@ -128,7 +545,15 @@ impl<'a> Drawable for GameScene<'a> {
     fn event(&mut self, event: Event) {
         match event {
             Event::KeyDown {
-                keycode: Some(Keycode::Left),
+                keycode: Some(key), ..
+            } if self.input.key(key, true) => {}
+            Event::KeyUp {
+                keycode: Some(key), ..
+            } => {
+                self.input.key(key, false);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::Minus),
                 ..
             } => {
                 if self.speed_mult != 0 {
@@ -137,155 +562,427 @@ impl<'a> Drawable for GameScene<'a> {
                 println!("{}x", self.speed_mult);
             }
             Event::KeyDown {
-                keycode: Some(Keycode::Right),
+                keycode: Some(Keycode::Equals),
                 ..
             } => {
                 self.speed_mult += 1;
                 println!("{}x", self.speed_mult);
             }
-            Event::MouseMotion {
-                mut xrel, mut yrel, ..
+            Event::KeyDown {
+                keycode: Some(Keycode::M),
+                ..
             } => {
-                if xrel.abs() > yrel.abs() {
-                    yrel = 0;
-                } else {
-                    xrel = 0;
-                }
-
-                self.place_mouse.set(true);
-
-                for game in &mut self.games {
-                    if !game.died {
-                        if let Controller::Human = game.controller {
-                            game.player_dir += xrel as f64 * 0.002;
-                            game.player_speed -= yrel as f64 * 0.4;
-                            if game.player_speed < 0. {
-                                game.player_speed = 0.;
-                            }
-                        }
+                self.input.captured = !self.input.captured;
+                self.input.mouse_throttle = 0.;
+                self.input.mouse_steer = 0.;
+                self.capture_changed.set(true);
+                println!(
+                    "Mouse {}",
+                    if self.input.captured {
+                        "captured, M to release"
+                    } else {
+                        "released"
                     }
-                }
+                );
+            }
+            Event::MouseMotion { xrel, yrel, .. } => {
+                self.input.mouse(xrel, yrel);
             }
             _ => {}
         }
     }
 
     fn update(&mut self, dt: f64) {
-        self.last_fitness_improvment += dt;
+        for _ in 0..self.clock.advance(dt * self.speed_mult as f64) {
+            self.sim_step();
+        }
+
+        let interpolation = self.clock.interpolation();
+        for game in &mut self.games {
+            game.interpolation = interpolation;
+        }
+    }
+
+    fn draw(&self, canvas: &mut Canvas<Window>, position: &Position, settings: DrawSettings) {
+        self.im.draw(canvas, position, settings);
+        if SHOW_HEATMAP {
+            self.heatmap_im.draw(canvas, position, settings);
+        }
 
-        for i in 0..self.speed_mult {
-            let mut dt_ = dt;
-            while dt_ < MIN_DT {
-                for game in &mut self.games {
-                    game.update(MIN_DT);
+        if SHOW_GHOSTS {
+            let r =
+                position.into_rect_with_size(self.map.width as u32, self.map.get_height() as u32);
+            let time = self.episode_time + self.clock.interpolation() * SIM_DT;
+            for (ghost, human) in self.ghosts.on_map(MAP_PATH) {
+                if let Some(car) = ghost.state_at(time) {
+                    let texture = if human {
+                        &*CAR_TEXTURE_GHOST_PLAYER
+                    } else {
+                        &*CAR_TEXTURE_GHOST
+                    };
+                    draw_car(canvas, r, &car, texture);
                 }
-                dt_ -= MIN_DT;
             }
-            for game in &mut self.games {
-                game.update(dt_);
+        }
+
+        for (i, game) in self.games.iter().enumerate() {
+            if game.controller.species().is_some() && i > SHOW {
+                continue;
             }
+            game.draw(canvas, position, settings);
         }
 
-        if self.last_fitness_improvment > 10. {
-            self.evolve();
+        if self.capture_changed.replace(false) {
+            unsafe {
+                if let Some(mouse) = &MOUSE {
+                    mouse.show_cursor(!self.input.captured);
+                    mouse.set_relative_mouse_mode(self.input.captured);
+                }
+            }
+        }
+    }
+}
+
+impl GameScene<'_> {
+    fn compile_networks(&mut self) {
+        let networks = self
+            .games
+            .iter()
+            .filter_map(|x| x.controller.genome().map(Network::compile))
+            .collect::<Vec<_>>();
+        self.evaluator = BatchEvaluator::new(&networks);
+    }
+
+    fn step_games(&mut self, dt: f64) {
+        let (sensed, outputs) = self.drive();
+        if RECORD_DEMOS {
+            for (i, game) in self.games.iter().enumerate() {
+                if game.controller.is_human() && game.running() {
+                    self.demos.record(&sensed[i], game.action);
+                }
+            }
         }
 
+        let was_running = self.games.iter().map(|x| x.running()).collect::<Vec<_>>();
         for game in &mut self.games {
-            if game.improved {
-                self.last_fitness_improvment = 0.;
-                game.improved = false;
+            game.update(dt);
+        }
+        if CAR_COLLISIONS {
+            self.collide_cars();
+        }
+
+        for (i, game) in self.games.iter().enumerate() {
+            if let (true, Some(reason)) = (was_running[i], game.termination) {
+                self.deaths.events.push(DeathEvent {
+                    car: i,
+                    driver: game.controller.kind(),
+                    species: game.controller.species(),
+                    time: game.time,
+                    pos: game.car.pos,
+                    reason,
+                });
+                if reason.crashed() {
+                    self.heatmap.add(game.car.pos);
+                }
             }
-            if let Controller::Human = game.controller {
-                self.last_fitness_improvment = 0.;
+        }
+        for game in &mut self.games {
+            if !game.died {
+                game.score(self.fitness);
             }
         }
 
-        for game in &self.games {
-            if !game.died {
-                return;
+        if let Some(replay) = &mut self.replay {
+            replay.record(&self.games, &sensed, &outputs);
+        }
+    }
+
+    fn solid_bodies(&self) -> Vec<Body> {
+        self.games
+            .iter()
+            .filter(|x| x.running() && x.solid)
+            .map(|x| x.body())
+            .collect()
+    }
+
+    // Crashes solid cars that overlap. All cars start on top of each other, so a car only
+    // becomes solid once it's clear of every other car.
+    fn collide_cars(&mut self) {
+        let running = (0..self.games.len())
+            .filter(|&i| self.games[i].running())
+            .collect::<Vec<_>>();
+        let bodies = running
+            .iter()
+            .map(|&i| self.games[i].body())
+            .collect::<Vec<_>>();
+
+        let mut touching = vec![false; running.len()];
+        let mut crashed = vec![false; running.len()];
+        for a in 0..running.len() {
+            for b in a + 1..running.len() {
+                if bodies[a].overlaps(&bodies[b]) {
+                    touching[a] = true;
+                    touching[b] = true;
+                    if self.games[running[a]].solid && self.games[running[b]].solid {
+                        crashed[a] = true;
+                        crashed[b] = true;
+                    }
+                }
             }
         }
 
-        self.evolve();
+        for (k, &i) in running.iter().enumerate() {
+            let game = &mut self.games[i];
+            if crashed[k] {
+                game.collisions += 1;
+                game.stop(Termination::Collision);
+            }
+            if !touching[k] {
+                game.solid = true;
+            }
+        }
     }
 
-    fn draw(&self, canvas: &mut Canvas<Window>, position: &Position, settings: DrawSettings) {
-        self.im.draw(canvas, position, settings);
+    fn sim_step(&mut self) {
+        self.step_games(SIM_DT);
+        self.episode_time += SIM_DT;
+        self.last_fitness_improvment += SIM_DT;
+
+        // The reference car doesn't keep the generation going, it gets as long as the population
+        for game in &mut self.games {
+            if game.improved && !game.controller.is_reference() {
+                self.last_fitness_improvment = 0.;
+            }
+            game.improved = false;
+            if game.controller.is_human() {
+                self.last_fitness_improvment = 0.;
+            }
+        }
 
-        // Find best
+        let all_stopped = self
+            .games
+            .iter()
+            .all(|x| !x.running() || x.controller.is_reference());
+        if self.last_fitness_improvment > 10. || all_stopped {
+            self.evolve();
+        }
+    }
 
+    // Lets the controller of every running car act on what it senses, after evaluating the networks
+    // being evolved in one batch. Returns what each car sensed and the outputs of its network,
+    // empty for other cars.
+    fn drive(&mut self) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let bodies = if self.sensors.car_rays {
+            self.solid_bodies()
+        } else {
+            Vec::new()
+        };
+
+        let mut sensed = vec![Vec::new(); self.games.len()];
+        let mut processed = vec![Vec::new(); self.games.len()];
         for (i, game) in self.games.iter().enumerate() {
-            match game.controller {
-                Controller::NEAT(_, _) if i > SHOW => {
-                    continue;
+            let evolved = game.controller.genome().is_some();
+            if !game.controller.senses() {
+                continue;
+            }
+            if game.running() {
+                sensed[i] = game.sense(&bodies);
+                processed[i] = sensed[i].clone();
+                // Only the cars being evolved shape the input statistics
+                if evolved {
+                    self.preprocessor.process(&mut processed[i]);
+                } else {
+                    self.preprocessor
+                        .process_without_learning(&mut processed[i]);
                 }
-                _ => {}
+            } else if evolved {
+                // The batch needs inputs for every evolved car, dead ones get zeros
+                processed[i] = vec![0.; self.sensors.nr_inputs()];
             }
-            game.draw(canvas, position, settings);
         }
 
-        if self.place_mouse.replace(false) {
-            unsafe {
-                let (w, h) = canvas.window().size();
-                if let Some(mouse) = &MOUSE {
-                    mouse.warp_mouse_in_window(canvas.window(), w as i32 / 2, h as i32 / 2);
-                    mouse.show_cursor(false);
-                    mouse.set_relative_mouse_mode(true);
-                }
+        // In the order the networks were compiled
+        let mut results = vec![Vec::new(); self.games.len()];
+        if self.evaluator.len() != 0 {
+            let evolved = (0..self.games.len())
+                .filter(|&i| self.games[i].controller.genome().is_some())
+                .collect::<Vec<_>>();
+            let mut inputs = Vec::with_capacity(self.evaluator.len() * self.evaluator.nr_ins);
+            for &i in &evolved {
+                inputs.extend_from_slice(&processed[i]);
+            }
+
+            let nr_outs = self.evaluator.nr_outs;
+            let mut outputs = vec![0.; self.evaluator.len() * nr_outs];
+            self.evaluator.evaluate(&inputs, &mut outputs);
+            for (&i, res) in evolved.iter().zip(outputs.chunks(nr_outs)) {
+                results[i] = res.to_vec();
+            }
+        }
+
+        for (i, game) in self.games.iter_mut().enumerate() {
+            if !game.running() {
+                continue;
             }
+            let obs = Observation {
+                inputs: &processed[i],
+                outputs: &results[i],
+                car: &game.car,
+                map: self.map,
+                time: game.time,
+                human: &self.input,
+            };
+            game.action = game.controller.act(&obs);
         }
+        (sensed, results)
     }
-}
 
-impl GameScene<'_> {
     fn evolve(&mut self) {
         self.last_fitness_improvment = 0.;
-        let has_human = self.games.iter().any(|x| {
-            if let Controller::Human = x.controller {
-                true
-            } else {
-                false
+        let mut reasons: Vec<(Option<Termination>, usize)> = Vec::new();
+        for game in &self.games {
+            self.ghosts.offer(game, MAP_PATH, SIM_DT);
+            match reasons.iter_mut().find(|x| x.0 == game.termination) {
+                Some(x) => x.1 += 1,
+                None => reasons.push((game.termination, 1)),
             }
-        });
+        }
+        println!("Stopped: {:?}", reasons);
+        self.episode_time = 0.;
+
+        if let Err(e) = self.deaths.append_to(DEATHS_PATH, self.generation) {
+            println!("Can't write deaths: {}", e);
+        }
+        self.deaths.events.clear();
+        self.heatmap_im = self.heatmap.to_image(self.map.width, self.map.get_height());
 
         let mut fitnesses = Vec::with_capacity(POP_SIZE);
         let mut species: Vec<Vec<(Genome, usize)>> = Vec::new();
 
-        for (i, game) in self.games.drain(..).enumerate() {
-            match game.controller {
-                Controller::NEAT(genome, species_idx) => {
+        let mut kept: Vec<Box<dyn Controller + Send>> = Vec::new();
+        let mut reference_fitness = None;
+
+        for mut game in self.games.drain(..) {
+            match (game.controller.genome(), game.controller.species()) {
+                (Some(genome), Some(species_idx)) => {
                     while species.len() <= species_idx {
                         species.push(Vec::new());
                     }
-                    species[species_idx].push((genome, i));
-                    fitnesses.push(game.best_score);
+                    let imitation = if IMITATION_WEIGHT != 0. {
+                        self.demos
+                            .score(genome, &self.preprocessor, self.decoder)
+                            .unwrap_or(0.)
+                    } else {
+                        0.
+                    };
+                    species[species_idx].push((genome.clone(), fitnesses.len()));
+                    fitnesses.push(game.best_score + IMITATION_WEIGHT * imitation);
+                }
+                _ => {
+                    if game.controller.is_reference() {
+                        reference_fitness = Some(game.best_score);
+                    }
+                    game.controller.reset();
+                    kept.push(game.controller);
                 }
-                _ => {}
             }
         }
 
-        let new_population = next_generation(species.clone(), fitnesses, &mut self.g_id, true);
+        clean_fitnesses(&mut fitnesses);
+        let new_population = next_generation(
+            species.clone(),
+            fitnesses.clone(),
+            &mut self.g_id,
+            &mut self.phylogeny,
+            OFFSPRING_SCHEME,
+            true,
+        );
+
+        let wall_time = self.generation_start.elapsed();
+        let mut stats = GenerationStats::collect(
+            self.generation,
+            &species,
+            &fitnesses,
+            self.g_id,
+            wall_time.as_secs() as f64 + wall_time.subsec_millis() as f64 / 1000.,
+        );
+        stats.reference_fitness = reference_fitness;
+        if let Err(e) = stats.append_to(STATS_PATH) {
+            println!("Can't write stats: {}", e);
+        }
+
+        let champion = species.iter().flatten().max_by(|(_, idx1), (_, idx2)| {
+            fitnesses[*idx1].partial_cmp(&fitnesses[*idx2]).unwrap()
+        });
+        if let Some((genome, idx)) = champion {
+            let file = File::create(CHAMPION_PATH).unwrap();
+            serialize_into(file, &(self.generation, fitnesses[*idx], genome))
+                .expect("Can't save champion");
+        }
+
+        self.generation += 1;
+        self.generation_start = Instant::now();
+
+        let new_species = class_species(new_population, species, &mut self.phylogeny);
 
         println!("Saving...");
 
-        let mut file = File::create(SAVE_PATH).unwrap();
-        serialize_into(file, &(new_population.clone(), self.g_id)).expect("Can't save");
+        if RECORD_DEMOS && kept.iter().any(|x| x.is_human()) {
+            if let Err(e) = self.demos.save(DEMO_PATH) {
+                println!("Can't save demonstrations: {}", e);
+            }
+        }
+
+        if let Some(replay) = self.replay.take() {
+            if let Err(e) = replay.save(REPLAY_DIR, REPLAYS_KEPT) {
+                println!("Can't save replay: {}", e);
+            }
+        }
+
+        let save = Save {
+            version: SAVE_VERSION,
+            species: new_species
+                .iter()
+                .map(|x| x.iter().map(|(genome, _)| genome.clone()).collect())
+                .collect(),
+            g_id: self.g_id,
+            phylogeny: self.phylogeny.clone(),
+            preprocessor: self.preprocessor.clone(),
+            decoder: self.decoder,
+            sensors: self.sensors.clone(),
+            ghosts: self.ghosts.clone(),
+            heatmap: self.heatmap.clone(),
+        };
+        let file = File::create(SAVE_PATH).unwrap();
+        serialize_into(file, &save).expect("Can't save");
+        if let Err(e) = self.phylogeny.export(PHYLOGENY_PATH) {
+            println!("Can't export phylogeny: {}", e);
+        }
         println!("Done");
 
-        for (i, species) in class_species(new_population, species)
-            .into_iter()
-            .enumerate()
-        {
+        for (i, species) in new_species.into_iter().enumerate() {
             for (genome, _) in species {
-                self.games.push(Game {
-                    controller: Controller::NEAT(genome, i),
-                    ..Game::new_human(self.map)
-                });
+                let controller = Neat {
+                    genome,
+                    species: i,
+                    decoder: self.decoder,
+                };
+                self.games
+                    .push(Game::new(self.map, self.sensors, Box::new(controller)));
             }
         }
+        for controller in kept {
+            self.games
+                .push(Game::new(self.map, self.sensors, controller));
+        }
+
+        self.compile_networks();
+        self.start_replay();
+    }
 
-        if has_human {
-            self.games.push(Game::new_human(self.map));
+    fn start_replay(&mut self) {
+        if RECORD_REPLAYS {
+            self.replay = Some(Replay::new(self.generation, MAP_PATH, SIM_DT, &self.games));
         }
     }
 }
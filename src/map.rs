@@ -1,12 +1,40 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::time::Instant;
 use std::u64;
+
+use rand::{thread_rng, Rng};
 use ytesrev::image::PngImage;
 
+const TRACK_GRADIENT_RADIUS: f64 = 3.;
+const CLEARANCE_CONTOUR: f64 = 10.;
+
+/// Where a ray stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    pub dist: f64,
+    pub point: (f64, f64),
+    pub tile: Option<(isize, isize)>, // The wall pixel hit, None if the ray reached its range
+    pub normal: (f64, f64),           // Of the wall side that was hit, zero if none was
+}
+
+/// Green pixels in the map. The start should be just past the line, the distances then grow
+/// from it all the way around the track and back to the line.
+#[derive(Clone)]
+pub struct FinishLine {
+    mask: Vec<bool>,
+    pub direction: f64,  // Crossing the line along this is completing a lap
+    pub lap_length: u64, // Distance from the line to itself around the track
+}
+
 #[derive(Clone)]
 pub struct Map {
     pub data: Vec<Tile>,
     pub width: usize,
     pub start: (usize, usize),
     pub end: (usize, usize),
+    clearance: Vec<f64>, // Distance from each pixel to the nearest wall pixel
+    pub finish: Option<FinishLine>,
 }
 
 impl Map {
@@ -14,6 +42,7 @@ impl Map {
         let mut data = vec![Tile::Wall; image.width * image.height];
 
         let mut start: Option<(usize, usize)> = None;
+        let mut mask = vec![false; image.width * image.height];
 
         for x in 0..image.width {
             for y in 0..image.height {
@@ -33,6 +62,10 @@ impl Map {
 
                         Tile::Ground(u64::MAX)
                     }
+                    (0, 255, 0) => {
+                        mask[idx] = true;
+                        Tile::Ground(u64::MAX)
+                    }
                     _ => Tile::Wall,
                 };
 
@@ -57,6 +90,9 @@ impl Map {
                 for (dx, dy) in &[(0, 1), (0, -1), (1, 0), (-1, 0)] {
                     let x = pos.0 + *dx as usize;
                     let y = pos.1 + *dy as usize;
+                    if mask.get(x + y * image.width) == Some(&true) {
+                        continue; // The track doesn't go on past the finish line
+                    }
                     if let Some(Tile::Ground(ref mut d)) = data.get_mut(x + y * image.width) {
                         if *d > dist {
                             *d = dist;
@@ -74,15 +110,26 @@ impl Map {
             visiting = next;
         }
 
+        let finish = if mask.contains(&true) {
+            Some(FinishLine::new(mask, &mut data, image.width))
+        } else {
+            None
+        };
+        let clearance = distance_field(&data, image.width);
+
         Map {
             data,
             width: image.width,
             start,
             end,
+            clearance,
+            finish,
         }
     }
 
-    pub fn into_image(self) -> PngImage {
+    /// Shows the map as the cars see it, with contour lines of the clearance every
+    /// `CLEARANCE_CONTOUR` pixels if `clearance_overlay` is set
+    pub fn into_image(self, clearance_overlay: bool) -> PngImage {
         let mut im_data = vec![255; 4 * self.data.len()];
         let mut max = 256;
         if let Some(Tile::Ground(n)) = self.data.get(self.end.0 + self.end.1 * self.width) {
@@ -90,8 +137,18 @@ impl Map {
         }
 
         for (i, x) in self.data.into_iter().enumerate() {
+            let on_contour = clearance_overlay && {
+                let c = self.clearance[i] / CLEARANCE_CONTOUR;
+                (c - c.round()).abs() <= 0.05
+            };
+            let on_finish = match &self.finish {
+                Some(finish) => finish.mask[i],
+                None => false,
+            };
             let col = match x {
                 Tile::Wall => (0, 0, 0),
+                Tile::Ground(_) if on_finish => (0, 255, 0),
+                Tile::Ground(_) if on_contour => (255, 255, 255),
                 Tile::Ground(n) => {
                     let g;
                     if n < 5 {
@@ -118,13 +175,483 @@ impl Map {
         }
     }
 
+    /// Whether the pixel at the given position is a wall. Everything outside the map is.
+    pub fn is_wall(&self, x: f64, y: f64) -> bool {
+        if x < 0. || y < 0. || x >= self.width as f64 || y >= self.get_height() as f64 {
+            return true;
+        }
+        match self.data[x as usize + y as usize * self.width] {
+            Tile::Wall => true,
+            Tile::Ground(_) => false,
+        }
+    }
+
+    fn is_wall_tile(&self, x: isize, y: isize) -> bool {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.get_height() {
+            return true;
+        }
+        match self.data[x as usize + y as usize * self.width] {
+            Tile::Wall => true,
+            Tile::Ground(_) => false,
+        }
+    }
+
+    /// Distance from the start to the farthest reachable pixel
+    pub fn end_distance(&self) -> u64 {
+        match self.data[self.end.0 + self.end.1 * self.width] {
+            Tile::Ground(n) => n.max(1),
+            Tile::Wall => 1,
+        }
+    }
+
+    pub fn on_finish(&self, pos: (f64, f64)) -> bool {
+        match &self.finish {
+            Some(finish) if !self.is_wall(pos.0, pos.1) => {
+                finish.mask[pos.0 as usize + pos.1 as usize * self.width]
+            }
+            _ => false,
+        }
+    }
+
+    /// Distance to the nearest wall pixel, zero inside walls and outside the map
+    pub fn clearance(&self, pos: (f64, f64)) -> f64 {
+        if self.is_wall(pos.0, pos.1) {
+            return 0.;
+        }
+        self.clearance[pos.0 as usize + pos.1 as usize * self.width]
+    }
+
+    /// Follows a ray until it enters a wall or has gone `max_range`. Everything outside the map is
+    /// wall, so with an infinite range it always hits something. Open space is skipped using the
+    /// distance field, the last stretch is traversed exactly, pixel by pixel.
+    pub fn cast_ray(&self, from: (f64, f64), angle: f64, max_range: f64) -> RayHit {
+        let (sin, cos) = angle.sin_cos();
+
+        // The clearance is between pixel centers, any point of a pixel can be up to half a
+        // diagonal closer to any point of the wall
+        let mut skip = 0.;
+        loop {
+            let c = self.clearance((from.0 + cos * skip, from.1 + sin * skip)) - 1.5;
+            if c < 1. || skip + c > max_range {
+                break;
+            }
+            skip += c;
+        }
+
+        self.traverse_ray(from, angle, max_range, skip)
+    }
+
+    /// Visits every pixel the ray passes, starting `skip` along it (Amanatides & Woo)
+    fn traverse_ray(&self, from: (f64, f64), angle: f64, max_range: f64, skip: f64) -> RayHit {
+        let (sin, cos) = angle.sin_cos();
+        let origin = (from.0 + cos * skip, from.1 + sin * skip);
+        let mut tile = (origin.0.floor() as isize, origin.1.floor() as isize);
+
+        let hit = |dist: f64, tile, normal| RayHit {
+            dist,
+            point: (from.0 + cos * dist, from.1 + sin * dist),
+            tile,
+            normal,
+        };
+
+        if self.is_wall_tile(tile.0, tile.1) {
+            return hit(skip, Some(tile), (0., 0.));
+        }
+
+        // Distance along the ray to the next pixel border on each axis, and between borders
+        let step = (cos.signum() as isize, sin.signum() as isize);
+        let next_border = |pos: f64, dir: f64| {
+            if dir > 0. {
+                (pos.floor() + 1. - pos) / dir
+            } else if dir < 0. {
+                (pos - pos.floor()) / -dir
+            } else {
+                f64::INFINITY
+            }
+        };
+        let mut t_max = (
+            skip + next_border(origin.0, cos),
+            skip + next_border(origin.1, sin),
+        );
+        let t_delta = (1. / cos.abs(), 1. / sin.abs());
+
+        loop {
+            let (dist, normal) = if t_max.0 < t_max.1 {
+                tile.0 += step.0;
+                t_max.0 += t_delta.0;
+                (t_max.0 - t_delta.0, (-step.0 as f64, 0.))
+            } else {
+                tile.1 += step.1;
+                t_max.1 += t_delta.1;
+                (t_max.1 - t_delta.1, (0., -step.1 as f64))
+            };
+
+            if dist > max_range {
+                return hit(max_range, None, (0., 0.));
+            }
+            if self.is_wall_tile(tile.0, tile.1) {
+                return hit(dist, Some(tile), normal);
+            }
+        }
+    }
+
+    /// Whether all corners of the rectangle, placed like in `footprint_hits_wall`, are on the map
+    pub fn footprint_in_bounds(&self, center: (f64, f64), dir: f64, size: (f64, f64)) -> bool {
+        let (sin, cos) = dir.sin_cos();
+        let (hl, hw) = (size.0 / 2., size.1 / 2.);
+
+        [(hl, hw), (-hl, hw), (-hl, -hw), (hl, -hw)]
+            .iter()
+            .all(|&(x, y)| {
+                let x_ = center.0 + x * cos - y * sin;
+                let y_ = center.1 + x * sin + y * cos;
+                x_ >= 0. && y_ >= 0. && x_ < self.width as f64 && y_ < self.get_height() as f64
+            })
+    }
+
+    /// Whether the outline of a `size.0` by `size.1` rectangle centered at `center`, with its
+    /// length along `dir`, touches a wall
+    pub fn footprint_hits_wall(&self, center: (f64, f64), dir: f64, size: (f64, f64)) -> bool {
+        let (sin, cos) = dir.sin_cos();
+        let (hl, hw) = (size.0 / 2., size.1 / 2.);
+
+        let corners = [(hl, hw), (-hl, hw), (-hl, -hw), (hl, -hw)];
+        for i in 0..4 {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            let len = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+            let samples = (len * 2.).ceil() as usize; // Every half pixel
+
+            for j in 0..samples {
+                let t = j as f64 / samples as f64;
+                let local = (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+                let x = center.0 + local.0 * cos - local.1 * sin;
+                let y = center.1 + local.0 * sin + local.1 * cos;
+                if self.is_wall(x, y) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// The direction in which the distance from the start grows fastest around `pos`, found by
+    /// comparing the distances `TRACK_GRADIENT_RADIUS` pixels away on each axis. None if there's
+    /// no reachable ground around to compare.
+    pub fn track_direction(&self, pos: (f64, f64)) -> Option<f64> {
+        let dist_at = |x: f64, y: f64| {
+            if self.is_wall(x, y) {
+                return None;
+            }
+            match self.data[x as usize + y as usize * self.width] {
+                Tile::Ground(n) if n != u64::MAX => Some(n as f64),
+                _ => None,
+            }
+        };
+
+        let r = TRACK_GRADIENT_RADIUS;
+        let center = dist_at(pos.0, pos.1);
+        // Central difference, or one-sided next to a wall
+        let diff = |before: Option<f64>, after: Option<f64>| match (before, after, center) {
+            (Some(a), Some(b), _) => Some((b - a) / 2.),
+            (Some(a), None, Some(c)) => Some(c - a),
+            (None, Some(b), Some(c)) => Some(b - c),
+            _ => None,
+        };
+
+        let dx = diff(dist_at(pos.0 - r, pos.1), dist_at(pos.0 + r, pos.1)).unwrap_or(0.);
+        let dy = diff(dist_at(pos.0, pos.1 - r), dist_at(pos.0, pos.1 + r)).unwrap_or(0.);
+        if dx == 0. && dy == 0. {
+            None
+        } else {
+            Some(dy.atan2(dx))
+        }
+    }
+
     pub fn get_height(&self) -> usize {
         self.data.len() / self.width
     }
 }
 
+impl FinishLine {
+    // Called with the distances filled in, the line itself is set to distance zero
+    fn new(mask: Vec<bool>, data: &mut [Tile], width: usize) -> FinishLine {
+        let dist = |data: &[Tile], i: usize| match data.get(i) {
+            Some(Tile::Ground(d)) if *d != u64::MAX && !mask[i] => Some(*d),
+            _ => None,
+        };
+
+        let neighbours = |i: usize| {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            [(1, 0), (-1, 0), (0, 1), (0, -1)]
+                .iter()
+                .map(move |&(dx, dy)| (dx, dy, x + dx, y + dy))
+                .filter(|&(_, _, x, y)| x >= 0 && y >= 0 && (x as usize) < width)
+                .map(move |(dx, dy, x, y)| (dx, dy, x as usize + y as usize * width))
+        };
+
+        let line = (0..mask.len()).filter(|&i| mask[i]).collect::<Vec<_>>();
+        let lap_length = line
+            .iter()
+            .flat_map(|&i| neighbours(i))
+            .filter_map(|(_, _, j)| dist(data, j))
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        // Neighbours at the start of the lap are ahead of the line, those at the end behind it
+        let mut direction = (0., 0.);
+        for &i in &line {
+            for (dx, dy, j) in neighbours(i) {
+                if let Some(d) = dist(data, j) {
+                    let sign = if d < lap_length / 2 { 1. } else { -1. };
+                    direction.0 += dx as f64 * sign;
+                    direction.1 += dy as f64 * sign;
+                }
+            }
+        }
+
+        for &i in &line {
+            data[i] = Tile::Ground(0);
+        }
+
+        FinishLine {
+            mask,
+            direction: direction.1.atan2(direction.0),
+            lap_length,
+        }
+    }
+}
+
+// Felzenszwalb & Huttenlocher's squared distance transform of one row or column, in place.
+// `f` is zero at walls and infinite elsewhere on the first pass.
+fn distance_transform_1d(f: &mut [f64]) {
+    let n = f.len();
+    let mut d = vec![0.; n];
+    let mut v = vec![0; n]; // Locations of the parabolas in the lower envelope
+    let mut z = vec![0.; n + 1]; // Boundaries between them
+    let mut k = 0;
+    z[0] = f64::NEG_INFINITY;
+    z[1] = f64::INFINITY;
+
+    let intersection = |f: &[f64], q: usize, p: usize| {
+        ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2 * q - 2 * p) as f64
+    };
+
+    for q in 1..n {
+        if f[q] == f64::INFINITY {
+            continue;
+        }
+        if f[v[k]] == f64::INFINITY {
+            v[k] = q;
+            continue;
+        }
+        let mut s = intersection(f, q, v[k]);
+        while s <= z[k] {
+            k -= 1;
+            s = intersection(f, q, v[k]);
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = s;
+        z[k + 1] = f64::INFINITY;
+    }
+
+    k = 0;
+    for (q, out) in d.iter_mut().enumerate() {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let dq = q as f64 - v[k] as f64;
+        *out = dq * dq + f[v[k]];
+    }
+    f.copy_from_slice(&d);
+}
+
+// Euclidean distance from every pixel to the nearest wall, with a wall all around the map
+fn distance_field(data: &[Tile], width: usize) -> Vec<f64> {
+    let height = data.len() / width;
+    let (pw, ph) = (width + 2, height + 2);
+
+    let mut field = vec![0.; pw * ph];
+    for y in 0..height {
+        for x in 0..width {
+            if let Tile::Ground(_) = data[x + y * width] {
+                field[x + 1 + (y + 1) * pw] = f64::INFINITY;
+            }
+        }
+    }
+
+    let mut column = vec![0.; ph];
+    for x in 0..pw {
+        for y in 0..ph {
+            column[y] = field[x + y * pw];
+        }
+        distance_transform_1d(&mut column);
+        for y in 0..ph {
+            field[x + y * pw] = column[y];
+        }
+    }
+    for row in field.chunks_mut(pw) {
+        distance_transform_1d(row);
+    }
+
+    let mut clearance = Vec::with_capacity(data.len());
+    for y in 0..height {
+        for x in 0..width {
+            clearance.push(field[x + 1 + (y + 1) * pw].sqrt());
+        }
+    }
+    clearance
+}
+
 #[derive(Clone, Copy)]
 pub enum Tile {
     Ground(u64),
     Wall,
 }
+
+fn load_bundled_maps() -> Vec<(&'static str, Map)> {
+    [
+        "map.png",
+        "map2.png",
+        "map3.png",
+        "map-alt.png",
+        "map-lab.png",
+    ]
+    .iter()
+    .filter_map(|path| {
+        let image = PngImage::load_from_path(File::open(path).ok()?).ok()?;
+        Some((*path, Map::create_from_image(&image)))
+    })
+    .collect()
+}
+
+fn random_ground(map: &Map, rng: &mut impl Rng) -> (f64, f64) {
+    loop {
+        let pos = (
+            rng.gen_range(0., map.width as f64),
+            rng.gen_range(0., map.get_height() as f64),
+        );
+        if !map.is_wall(pos.0, pos.1) {
+            return pos;
+        }
+    }
+}
+
+// How rays were cast before, one pixel at a time
+fn march_ray(map: &Map, from: (f64, f64), angle: f64) -> f64 {
+    let mut at = from;
+    while !map.is_wall(at.0, at.1) {
+        at.0 += angle.cos();
+        at.1 += angle.sin();
+    }
+    ((at.0 - from.0).powi(2) + (at.1 - from.1).powi(2)).sqrt()
+}
+
+#[allow(unused)]
+pub fn test_cast_ray() {
+    const RAYS: usize = 10000;
+    let mut rng = thread_rng();
+
+    for (path, map) in load_bundled_maps() {
+        let mut corners = 0;
+        for _ in 0..RAYS {
+            let from = random_ground(&map, &mut rng);
+            let angle = rng.gen_range(-PI, PI);
+            let hit = map.cast_ray(from, angle, f64::INFINITY);
+
+            // The hit is a wall, and the point it was entered from isn't
+            let tile = hit.tile.expect("Ray escaped the map");
+            assert!(map.is_wall_tile(tile.0, tile.1));
+            let before = (
+                hit.point.0 + hit.normal.0 * 1e-6,
+                hit.point.1 + hit.normal.1 * 1e-6,
+            );
+            assert!(!map.is_wall(before.0, before.1));
+            assert!(hit.normal.0.abs() + hit.normal.1.abs() == 1.);
+
+            // Skipping ahead with the distance field lands on the same wall
+            let exact = map.traverse_ray(from, angle, f64::INFINITY, 0.);
+            assert_eq!(exact.tile, hit.tile);
+            assert!((exact.dist - hit.dist).abs() < 1e-6);
+
+            // Marching stops within a step after the wall, unless it jumped a corner first
+            let marched = march_ray(&map, from, angle);
+            assert!(hit.dist <= marched + 1e-9);
+            if marched - hit.dist > 1. + 1e-9 {
+                corners += 1;
+            }
+
+            let limited = map.cast_ray(from, angle, hit.dist / 2.);
+            assert_eq!(limited.tile, None);
+            assert!((limited.dist - hit.dist / 2.).abs() < 1e-9);
+        }
+        println!(
+            "test_cast_ray: {} ok, marching skipped {} of {} walls",
+            path, corners, RAYS
+        );
+    }
+}
+
+#[allow(unused)]
+pub fn bench_cast_ray() {
+    const RAYS: usize = 100000;
+    let mut rng = thread_rng();
+
+    for (path, map) in load_bundled_maps() {
+        let rays = (0..RAYS)
+            .map(|_| (random_ground(&map, &mut rng), rng.gen_range(-PI, PI)))
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let marched = rays
+            .iter()
+            .map(|&(from, angle)| march_ray(&map, from, angle))
+            .sum::<f64>();
+        let march_time = start.elapsed() / RAYS as u32;
+
+        let start = Instant::now();
+        let cast = rays
+            .iter()
+            .map(|&(from, angle)| map.cast_ray(from, angle, f64::INFINITY).dist)
+            .sum::<f64>();
+        let cast_time = start.elapsed() / RAYS as u32;
+
+        println!(
+            "{}: marching {:?}, cast_ray {:?} per ray, {:.1} px on average",
+            path,
+            march_time,
+            cast_time,
+            cast / RAYS as f64
+        );
+        assert!(cast <= marched);
+    }
+}
+
+#[allow(unused)]
+pub fn test_distance_field() {
+    let mut rng = thread_rng();
+
+    for (path, map) in load_bundled_maps() {
+        let walls = (0..map.data.len())
+            .filter(|&i| map.is_wall_tile((i % map.width) as isize, (i / map.width) as isize))
+            .map(|i| ((i % map.width) as f64, (i / map.width) as f64))
+            .collect::<Vec<_>>();
+        let (w, h) = (map.width as f64, map.get_height() as f64);
+
+        for _ in 0..200 {
+            let pos = random_ground(&map, &mut rng);
+            let (x, y) = (pos.0.floor(), pos.1.floor());
+
+            // The nearest wall pixel, or the nearest pixel just outside the map
+            let border = x.min(y).min(w - 1. - x).min(h - 1. - y) + 1.;
+            let nearest = walls
+                .iter()
+                .map(|w| ((w.0 - x).powi(2) + (w.1 - y).powi(2)).sqrt())
+                .fold(border, f64::min);
+
+            assert!((map.clearance(pos) - nearest).abs() < 1e-9);
+        }
+        println!("test_distance_field: {} ok", path);
+    }
+}
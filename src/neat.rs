@@ -10,15 +10,28 @@ use rand::Rng;
 
 use serde_derive::{Serialize, Deserialize};
 
+use crate::phylogeny::Phylogeny;
+
 const FACTOR_DISJOINT: f64 = 1.;
 const FACTOR_WDIFF: f64 = 0.2;
 const DIFF_THRESH: f64 = 4.;
+const MIN_FITNESS: f64 = 1e-3; // What the worst genome is shifted to when fitnesses aren't positive
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Genome {
     pub nr_ins: usize,
     pub nr_outs: usize,
     pub connections: HashMap<usize, Connection>,
+    #[serde(default)]
+    pub lineage: Lineage,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Lineage {
+    pub id: usize,
+    pub parents: Vec<usize>,
+    /// Stable id of the species this genome was bred in, see `Phylogeny`
+    pub species: Option<usize>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
@@ -56,6 +69,7 @@ impl Genome {
                 connections,
                 nr_ins: n_inputs,
                 nr_outs: n_outputs,
+                lineage: Lineage::default(),
             },
             id,
         )
@@ -64,16 +78,28 @@ impl Genome {
     pub fn evaluate(&self, inputs: &[f64]) -> Vec<f64> {
         assert_eq!(self.nr_ins, inputs.len());
 
+        let connections = self.sorted_connections();
         let mut res: Vec<f64> = Vec::new();
 
         for i in 0..self.nr_outs {
-            res.push(self.evaluate_node(self.nr_ins + i + 1, inputs));
+            res.push(self.evaluate_sorted(self.nr_ins + i + 1, inputs, &connections));
         }
 
         res
     }
 
-    pub fn evaluate_node(&self, node: usize, inputs: &[f64]) -> f64 {
+    // Enabled connections in innovation order. Summing in this order makes the output independent
+    // of the HashMap's iteration order, so equivalent genomes give bit-identical results.
+    fn sorted_connections(&self) -> Vec<&Connection> {
+        let mut ids = self.connections.keys().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.into_iter()
+            .map(|id| &self.connections[id])
+            .filter(|x| !x.disabled)
+            .collect()
+    }
+
+    fn evaluate_sorted(&self, node: usize, inputs: &[f64], connections: &[&Connection]) -> f64 {
         if node < self.nr_ins {
             return inputs[node];
         }
@@ -82,17 +108,60 @@ impl Genome {
         }
 
         let mut sum = 0.;
-        for connection in self.connections.values() {
-            if connection.disabled {
-                continue;
-            }
+        for connection in connections {
             if connection.to == node {
-                sum += self.evaluate_node(connection.from, inputs) * connection.weight;
+                sum +=
+                    self.evaluate_sorted(connection.from, inputs, connections) * connection.weight;
             }
         }
         (5. * sum).tanh()
     }
 
+    /// An equivalent genome without disabled connections and without nodes that can't reach an
+    /// output. Innovation numbers are kept, so it can still be compared with other genomes.
+    pub fn simplified(&self) -> Genome {
+        let mut useful = (0..self.nr_outs)
+            .map(|i| self.nr_ins + i + 1)
+            .collect::<Vec<_>>();
+        let mut stack = useful.clone();
+        while let Some(node) = stack.pop() {
+            for conn in self.connections.values() {
+                if !conn.disabled && conn.to == node && !useful.contains(&conn.from) {
+                    useful.push(conn.from);
+                    stack.push(conn.from);
+                }
+            }
+        }
+
+        Genome {
+            nr_ins: self.nr_ins,
+            nr_outs: self.nr_outs,
+            connections: self
+                .connections
+                .iter()
+                .filter(|(_, conn)| !conn.disabled && useful.contains(&conn.to))
+                .map(|(id, conn)| (*id, *conn))
+                .collect(),
+            lineage: self.lineage.clone(),
+        }
+    }
+
+    pub fn hidden_nodes(&self) -> usize {
+        let mut nodes = self
+            .connections
+            .values()
+            .flat_map(|x| vec![x.from, x.to])
+            .filter(|&x| x > self.nr_ins + self.nr_outs)
+            .collect::<Vec<_>>();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes.len()
+    }
+
+    pub fn enabled_connections(&self) -> usize {
+        self.connections.values().filter(|x| !x.disabled).count()
+    }
+
     pub fn merge_with(&self, other: &Genome, other_better: Option<bool>) -> Genome {
         let mut rng = thread_rng();
         let mut new_connections = HashMap::new();
@@ -130,6 +199,7 @@ impl Genome {
             nr_ins: self.nr_ins,
             nr_outs: self.nr_outs,
             connections: new_connections,
+            lineage: Lineage::default(),
         }
     }
 
@@ -270,6 +340,106 @@ impl Genome {
             .output();
     }
 
+    pub fn to_svg(&self) -> String {
+        // Layer each node by its longest path from the inputs, outputs always on top
+        fn depth(node: usize, genome: &Genome, memo: &mut HashMap<usize, usize>) -> usize {
+            if let Some(d) = memo.get(&node) {
+                return *d;
+            }
+            let d = genome
+                .connections
+                .values()
+                .filter(|x| x.to == node)
+                .map(|x| depth(x.from, genome, memo) + 1)
+                .max()
+                .unwrap_or(0);
+            memo.insert(node, d);
+            d
+        }
+
+        let mut nodes = (0..self.nr_ins + self.nr_outs + 1).collect::<Vec<_>>();
+        nodes.extend(self.connections.values().flat_map(|x| vec![x.from, x.to]));
+        nodes.sort_unstable();
+        nodes.dedup();
+
+        let mut memo = HashMap::new();
+        let mut depths = nodes
+            .iter()
+            .map(|&n| (n, depth(n, self, &mut memo)))
+            .collect::<HashMap<_, _>>();
+        let max_depth = depths.values().cloned().max().unwrap_or(0).max(1);
+        for i in 0..self.nr_outs {
+            depths.insert(self.nr_ins + i + 1, max_depth);
+        }
+
+        let mut layers: Vec<Vec<usize>> = vec![Vec::new(); max_depth + 1];
+        for &n in &nodes {
+            layers[depths[&n]].push(n);
+        }
+
+        const SPACING: f64 = 50.;
+        let width = layers.iter().map(Vec::len).max().unwrap_or(1) as f64 * SPACING;
+        let height = (max_depth + 1) as f64 * SPACING;
+
+        let mut pos = HashMap::new();
+        for (d, layer) in layers.iter().enumerate() {
+            let offset = (width - layer.len() as f64 * SPACING) / 2.;
+            for (i, &n) in layer.iter().enumerate() {
+                let x = offset + (i as f64 + 0.5) * SPACING;
+                let y = height - (d as f64 + 0.5) * SPACING;
+                pos.insert(n, (x, y));
+            }
+        }
+
+        let mut res = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">",
+            width, height
+        );
+
+        let mut ids = self.connections.keys().collect::<Vec<_>>();
+        ids.sort_unstable();
+        for id in ids {
+            let conn = &self.connections[id];
+            let (x1, y1) = pos[&conn.from];
+            let (x2, y2) = pos[&conn.to];
+            res.push_str(&format!(
+                "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"{}\" stroke-width=\"{:.2}\"{}><title>{}: {:.2}</title></line>",
+                x1,
+                y1,
+                x2,
+                y2,
+                if conn.weight < 0. { "#c0392b" } else { "#2471a3" },
+                (conn.weight.abs() * 2.).clamp(0.5, 6.),
+                if conn.disabled { " stroke-dasharray=\"3,3\" opacity=\"0.4\"" } else { "" },
+                id,
+                conn.weight,
+            ));
+        }
+
+        for n in nodes {
+            let (x, y) = pos[&n];
+            let fill = match n {
+                n if n < self.nr_ins => "#f4d03f",
+                n if n == self.nr_ins => "#aaaaaa",
+                n if n <= self.nr_ins + self.nr_outs => "#58d68d",
+                _ => "#ffffff",
+            };
+            res.push_str(&format!(
+                "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"12\" fill=\"{}\" stroke=\"black\"/>\
+                 <text x=\"{:.1}\" y=\"{:.1}\" font-size=\"10\" text-anchor=\"middle\">{}</text>",
+                x,
+                y,
+                fill,
+                x,
+                y + 4.,
+                n
+            ));
+        }
+
+        res.push_str("</svg>");
+        res
+    }
+
     fn dist(&self, other: &Genome) -> f64 {
         // We treat disjoint and excess as the same thing
         let mut nr_disjoint = 0;
@@ -297,8 +467,15 @@ impl Genome {
 pub fn class_species(
     population: Vec<Genome>,
     old_species: Vec<Vec<(Genome, usize)>>,
+    phylogeny: &mut Phylogeny,
 ) -> Vec<Vec<(Genome, usize)>> {
     let mut species: Vec<Vec<(Genome, usize)>> = vec![Vec::new(); old_species.len()];
+    let mut ids = (0..old_species.len())
+        .map(|n| match phylogeny.species_id(n) {
+            Some(id) => id,
+            None => phylogeny.new_species(None),
+        })
+        .collect::<Vec<_>>();
 
     for (i, genome) in population.into_iter().enumerate() {
         let mut spec_idx = None;
@@ -318,83 +495,156 @@ pub fn class_species(
         if let Some(idx) = spec_idx {
             species[idx].push((genome, i));
         } else {
+            ids.push(phylogeny.new_species(genome.lineage.species));
             species.push(vec![(genome, i)]);
         }
     }
 
+    let mut current = Vec::new();
+    for (sp, id) in species.iter().zip(ids) {
+        if sp.is_empty() {
+            phylogeny.species[id].extinct = Some(phylogeny.generation);
+        } else {
+            phylogeny.species[id].sizes.push(sp.len());
+            current.push(id);
+        }
+    }
+    phylogeny.current = current;
+    phylogeny.generation += 1;
+
     species.retain(|x| !x.is_empty());
 
     species
 }
 
+// Non-finite fitnesses, from a NaN or an infinity in the fitness function, are made the worst
+// finite one. The offspring shares don't make sense otherwise.
+pub fn clean_fitnesses(fitnesses: &mut [f64]) {
+    let worst = fitnesses
+        .iter()
+        .cloned()
+        .filter(|x| x.is_finite())
+        .fold(f64::INFINITY, f64::min);
+    let worst = if worst.is_finite() { worst } else { 0. };
+
+    for x in fitnesses.iter_mut().filter(|x| !x.is_finite()) {
+        *x = worst;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OffspringScheme {
+    /// Offspring proportional to the species' shared fitness
+    Proportional,
+    /// Shared fitness scaled by 0.1, 0.5, 1.5 or 3 depending on how many standard deviations it
+    /// is from the mean species fitness
+    DeviationMultiplier,
+}
+
+/// Explicit fitness sharing: every fitness is divided by the size of its species. Fitnesses are
+/// first shifted so that the worst one is slightly positive, if any of them are zero or negative.
+/// The fitnesses should have been through `clean_fitnesses`.
+pub fn adjusted_fitnesses(species: &[Vec<(Genome, usize)>], fitnesses: &[f64]) -> Vec<f64> {
+    let min = species
+        .iter()
+        .flatten()
+        .map(|(_, idx)| fitnesses[*idx])
+        .filter(|x| x.is_finite())
+        .fold(f64::INFINITY, f64::min);
+    let shift = if min <= 0. { MIN_FITNESS - min } else { 0. };
+
+    let mut adj_fitness = vec![0.; fitnesses.len()];
+    for x in species {
+        for (_, idx) in x {
+            adj_fitness[*idx] = (fitnesses[*idx] + shift) / x.len() as f64;
+        }
+    }
+    adj_fitness
+}
+
+pub fn species_fitnesses(species: &[Vec<(Genome, usize)>], adj_fitness: &[f64]) -> Vec<f64> {
+    species
+        .iter()
+        .map(|x| x.iter().map(|(_, idx)| adj_fitness[*idx]).sum())
+        .collect()
+}
+
+/// The fraction of the next generation given to each species, summing to one
+pub fn offspring_shares(species_fitness: &[f64], scheme: OffspringScheme) -> Vec<f64> {
+    let (average, deviation) = mean_and_deviation(species_fitness);
+
+    let offspring = species_fitness
+        .iter()
+        .map(|&x| match scheme {
+            OffspringScheme::Proportional => x,
+            OffspringScheme::DeviationMultiplier => {
+                let mult = match x {
+                    x if x < average - deviation => 0.1,
+                    x if x < average => 0.5,
+                    x if x < average + deviation => 1.5,
+                    _ => 3.,
+                };
+                x * mult
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let total = offspring.iter().sum::<f64>();
+    if total > 0. {
+        offspring.iter().map(|x| x / total).collect()
+    } else {
+        vec![1. / offspring.len() as f64; offspring.len()]
+    }
+}
+
+fn mean_and_deviation(xs: &[f64]) -> (f64, f64) {
+    let mean = xs.iter().sum::<f64>() / xs.len() as f64;
+    let deviation = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>().sqrt();
+    (mean, deviation)
+}
+
 pub fn next_generation(
     mut species: Vec<Vec<(Genome, usize)>>,
     fitnesses: Vec<f64>,
     g_id: &mut usize,
+    phylogeny: &mut Phylogeny,
+    scheme: OffspringScheme,
     verbose: bool,
 ) -> Vec<Genome> {
     let mut rng = thread_rng();
 
+    // Indexed like `species` after empty species are removed below
+    let species_ids = (0..species.len())
+        .filter(|&i| !species[i].is_empty())
+        .map(|i| phylogeny.species_id(i))
+        .collect::<Vec<_>>();
+
+    species.retain(|x| !x.is_empty());
+
     let species_orig_size = species.iter().map(Vec::len).collect::<Vec<_>>();
 
+    // Offspring are allocated on the whole species, before the weak half is removed
+    let adj_fitness = adjusted_fitnesses(&species, &fitnesses);
+    let species_fitness = species_fitnesses(&species, &adj_fitness);
+    let offspring = offspring_shares(&species_fitness, scheme);
+
     // Remove bottom 50% of each species
     for x in &mut species {
         x.sort_unstable_by(|(_, idx1), (_, idx2)| {
-            fitnesses[*idx2].partial_cmp(&fitnesses[*idx1]).unwrap()
+            fitnesses[*idx2]
+                .partial_cmp(&fitnesses[*idx1])
+                .unwrap_or(Ordering::Equal)
         });
         x.truncate(x.len() / 2 + 1);
     }
-    species.retain(|x| !x.is_empty());
 
     let average_species_size =
         species.iter().map(Vec::len).sum::<usize>() as f64 / species.len() as f64;
 
-    let mut adj_fitness = vec![0.; fitnesses.len()];
-
-    for x in &species {
-        let len = x.len();
-        for (ind, idx) in x {
-            adj_fitness[*idx] = fitnesses[*idx] / len as f64;
-        }
-    }
-
-    let mut species_fitness = Vec::new();
-    for x in &species {
-        let mut tot_fit = 0.;
-        for (_, idx) in x {
-            tot_fit += adj_fitness[*idx];
-        }
-        species_fitness.push(tot_fit);
-    }
-
-    let average_species_fitness =
-        species_fitness.iter().sum::<f64>() / species_fitness.len() as f64;
-
-    let deviation = species_fitness
-        .iter()
-        .map(|x| (x - average_species_fitness).powi(2))
-        .sum::<f64>()
-        .sqrt();
-
-    let mut offspring: Vec<f64> = Vec::new();
-    for (i, sp) in species.iter().enumerate() {
-        let val = species_fitness[i];
-
-        let mult = match species_fitness[i] {
-            x if x < average_species_fitness - deviation => 0.1,
-            x if x < average_species_fitness => 0.5,
-            x if x < average_species_fitness + deviation => 1.5,
-            _ => 3.,
-        };
-
-        offspring.push(val * mult);
-    }
-
     let mut result: Vec<Genome> = Vec::new();
 
     for (i, sp) in species.iter().enumerate() {
-        let mut num_offspring =
-            offspring[i] / offspring.iter().sum::<f64>() * fitnesses.len() as f64;
+        let mut num_offspring = offspring[i] * fitnesses.len() as f64;
         if rng.gen::<f64>() < num_offspring % 1. {
             num_offspring += 1.;
         }
@@ -414,12 +664,19 @@ pub fn next_generation(
             );
         }
 
+        let species_id = species_ids[i];
         for i in 0..num_offspring {
             let (ind_1, idx_1) = &sp[rng.gen_range(0, sp.len())];
             let (ind_2, idx_2) = &sp[rng.gen_range(0, sp.len())];
 
             if rng.gen::<f64>() < 0.4 {
-                result.push(ind_1.clone());
+                let mut child = ind_1.clone();
+                child.lineage = Lineage {
+                    id: phylogeny.new_genome_id(),
+                    parents: vec![ind_1.lineage.id],
+                    species: species_id,
+                };
+                result.push(child);
                 continue;
             }
 
@@ -431,6 +688,11 @@ pub fn next_generation(
             };
 
             let mut merged = ind_1.merge_with(&ind_2, other_better);
+            merged.lineage = Lineage {
+                id: phylogeny.new_genome_id(),
+                parents: vec![ind_1.lineage.id, ind_2.lineage.id],
+                species: species_id,
+            };
             if rng.gen::<f64>() < 0.4 {
                 merged.mutate(g_id, is_small);
             }
@@ -440,7 +702,10 @@ pub fn next_generation(
     }
 
     if verbose {
-        println!("Fitness deviation: {:?}", deviation);
+        println!(
+            "Fitness deviation: {:?}",
+            mean_and_deviation(&species_fitness).1
+        );
     }
 
     rng.shuffle(&mut result);
@@ -448,6 +713,101 @@ pub fn next_generation(
     result
 }
 
+#[allow(unused)]
+pub fn test_fitness_sharing() {
+    let genome = Genome::init(1, 1).0;
+    let species_of = |idxs: &[usize]| {
+        idxs.iter()
+            .map(|&i| (genome.clone(), i))
+            .collect::<Vec<_>>()
+    };
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+    // A large species doesn't get more offspring just by being large
+    let species = vec![species_of(&[0, 1, 2, 3]), species_of(&[4])];
+    let fitnesses = vec![4., 4., 4., 4., 4.];
+    let adj = adjusted_fitnesses(&species, &fitnesses);
+    assert!(adj[..4].iter().all(|&x| close(x, 1.)));
+    assert!(close(adj[4], 4.));
+    let spf = species_fitnesses(&species, &adj);
+    assert!(close(spf[0], 4.) && close(spf[1], 4.));
+    let shares = offspring_shares(&spf, OffspringScheme::Proportional);
+    assert!(close(shares[0], 0.5) && close(shares[1], 0.5));
+
+    // Shares follow the species' mean fitness
+    let fitnesses = vec![1., 1., 1., 1., 3.];
+    let adj = adjusted_fitnesses(&species, &fitnesses);
+    let shares = offspring_shares(
+        &species_fitnesses(&species, &adj),
+        OffspringScheme::Proportional,
+    );
+    assert!(close(shares[0], 0.25) && close(shares[1], 0.75));
+
+    // Zero and negative fitnesses are shifted, the worst genome still gets a small share
+    let species = vec![species_of(&[0, 1]), species_of(&[2])];
+    let fitnesses = vec![-2., 0., 0.];
+    let adj = adjusted_fitnesses(&species, &fitnesses);
+    assert!(adj.iter().all(|&x| x > 0.));
+    assert!(close(adj[0], MIN_FITNESS / 2.));
+    assert!(close(adj[1], (2. + MIN_FITNESS) / 2.));
+    assert!(close(adj[2], 2. + MIN_FITNESS));
+    let shares = offspring_shares(
+        &species_fitnesses(&species, &adj),
+        OffspringScheme::Proportional,
+    );
+    assert!(close(shares.iter().sum::<f64>(), 1.));
+    assert!(shares[1] > shares[0]);
+
+    // All zero gives equal shares
+    let fitnesses = vec![0., 0., 0.];
+    let adj = adjusted_fitnesses(&species, &fitnesses);
+    let shares = offspring_shares(
+        &species_fitnesses(&species, &adj),
+        OffspringScheme::Proportional,
+    );
+    assert!(close(shares[0], 0.5) && close(shares[1], 0.5));
+
+    // The deviation multipliers favour the best species further
+    let spf = vec![1., 2., 3.];
+    let shares = offspring_shares(&spf, OffspringScheme::DeviationMultiplier);
+    let total = 1. * 0.5 + 2. * 1.5 + 3. * 1.5;
+    assert!(close(shares[0], 0.5 / total));
+    assert!(close(shares[1], 3. / total));
+    assert!(close(shares[2], 4.5 / total));
+
+    println!("test_fitness_sharing: ok");
+}
+
+#[allow(unused)]
+pub fn test_simplified() {
+    let mut rng = thread_rng();
+
+    for _ in 0..100 {
+        let (mut genome, mut g_id) = Genome::init(6, 2);
+        for _ in 0..30 {
+            genome.mutate(&mut g_id, false);
+            genome.mutate_add_node(&mut g_id);
+            genome.mutate_add_connection(&mut g_id);
+        }
+
+        let simplified = genome.simplified();
+        assert!(simplified.connections.values().all(|x| !x.disabled));
+        assert!(simplified.connections.len() <= genome.enabled_connections());
+
+        for _ in 0..100 {
+            let inputs = (0..6)
+                .map(|_| rng.gen_range(-100., 100.))
+                .collect::<Vec<f64>>();
+            let a = genome.evaluate(&inputs);
+            let b = simplified.evaluate(&inputs);
+            for (a, b) in a.iter().zip(b.iter()) {
+                assert_eq!(a.to_bits(), b.to_bits());
+            }
+        }
+    }
+    println!("test_simplified: ok");
+}
+
 const TABLE: &[(f64, f64, f64)] = &[(0., 0., 0.), (0., 1., 1.), (1., 0., 1.), (1., 1., 0.)];
 
 #[allow(unused)]
@@ -458,7 +818,8 @@ pub fn test_xor() {
 
     let mut last_best = pop[0].clone();
 
-    let mut old_species = class_species(pop.clone(), vec![]);
+    let mut phylogeny = Phylogeny::default();
+    let mut old_species = class_species(pop.clone(), vec![], &mut phylogeny);
 
     for extinction in 0..1 {
         for i in 0..100 {
@@ -515,10 +876,17 @@ pub fn test_xor() {
                 println!("Best fitness: {:?}", best.1);
             }
 
-            let species = class_species(pop, old_species);
+            let species = class_species(pop, old_species, &mut phylogeny);
             old_species = species.clone();
 
-            pop = next_generation(species, fitness, &mut g_id, i % 5 == 0);
+            pop = next_generation(
+                species,
+                fitness,
+                &mut g_id,
+                &mut phylogeny,
+                OffspringScheme::Proportional,
+                i % 5 == 0,
+            );
 
             println!("Done");
         }
@@ -535,3 +903,47 @@ pub fn test_xor() {
     }
     last_best.gen_graphviz("best".into());
 }
+
+#[allow(unused)]
+pub fn test_non_finite_fitness() {
+    let pop = (0..40).map(|_| Genome::init(2, 1).0).collect::<Vec<_>>();
+    let mut phylogeny = Phylogeny::default();
+    let species = class_species(pop, vec![], &mut phylogeny);
+    let g_id = Genome::init(2, 1).1;
+
+    let mut fitnesses = (0..40).map(|i| i as f64).collect::<Vec<_>>();
+    fitnesses[3] = f64::NAN;
+    fitnesses[7] = f64::INFINITY;
+    fitnesses[11] = f64::NEG_INFINITY;
+
+    // Raw, they may skew the offspring but mustn't panic
+    next_generation(
+        species.clone(),
+        fitnesses.clone(),
+        &mut g_id.clone(),
+        &mut phylogeny.clone(),
+        OffspringScheme::Proportional,
+        false,
+    );
+
+    clean_fitnesses(&mut fitnesses);
+    assert!(fitnesses.iter().all(|x| x.is_finite()));
+    assert_eq!(fitnesses[3], 0.);
+    assert_eq!(fitnesses[7], 0.);
+
+    let next = next_generation(
+        species,
+        fitnesses,
+        &mut g_id.clone(),
+        &mut phylogeny,
+        OffspringScheme::Proportional,
+        false,
+    );
+    assert!(next.len() > 20);
+
+    let mut none = vec![f64::NAN; 3];
+    clean_fitnesses(&mut none);
+    assert_eq!(none, vec![0.; 3]);
+
+    println!("test_non_finite_fitness: ok");
+}
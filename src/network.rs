@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use rand::{thread_rng, Rng};
+
+use crate::neat::Genome;
+
+/// A genome compiled into a flat list of nodes in evaluation order. Gives the same results as
+/// `Genome::evaluate`, bit for bit, without walking the connection map.
+#[derive(Debug, Clone)]
+pub struct Network {
+    pub nr_ins: usize,
+    pub nr_outs: usize,
+    // Slots 0..nr_ins are the inputs, nr_ins is the bias, the rest are computed in order
+    nr_slots: usize,
+    nodes: Vec<Node>,
+    outputs: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    slot: usize,
+    incoming: Vec<(usize, f64)>, // (slot, weight), in innovation order
+}
+
+impl Network {
+    pub fn compile(genome: &Genome) -> Network {
+        let mut ids = genome.connections.keys().collect::<Vec<_>>();
+        ids.sort_unstable();
+        let connections = ids
+            .into_iter()
+            .map(|id| &genome.connections[id])
+            .filter(|x| !x.disabled)
+            .collect::<Vec<_>>();
+
+        let mut slots: HashMap<usize, usize> = (0..genome.nr_ins + 1).map(|i| (i, i)).collect();
+        let mut nodes = Vec::new();
+
+        fn visit(
+            node: usize,
+            genome: &Genome,
+            connections: &[&crate::neat::Connection],
+            slots: &mut HashMap<usize, usize>,
+            nodes: &mut Vec<Node>,
+        ) -> usize {
+            if let Some(slot) = slots.get(&node) {
+                return *slot;
+            }
+
+            let mut incoming = Vec::new();
+            for conn in connections {
+                if conn.to == node {
+                    incoming.push((
+                        visit(conn.from, genome, connections, slots, nodes),
+                        conn.weight,
+                    ));
+                }
+            }
+
+            let slot = genome.nr_ins + 1 + nodes.len();
+            nodes.push(Node { slot, incoming });
+            slots.insert(node, slot);
+            slot
+        }
+
+        let outputs = (0..genome.nr_outs)
+            .map(|i| {
+                visit(
+                    genome.nr_ins + i + 1,
+                    genome,
+                    &connections,
+                    &mut slots,
+                    &mut nodes,
+                )
+            })
+            .collect();
+
+        Network {
+            nr_ins: genome.nr_ins,
+            nr_outs: genome.nr_outs,
+            nr_slots: genome.nr_ins + 1 + nodes.len(),
+            nodes,
+            outputs,
+        }
+    }
+
+    pub fn evaluate(&self, inputs: &[f64]) -> Vec<f64> {
+        assert_eq!(self.nr_ins, inputs.len());
+
+        let mut values = vec![0.; self.nr_slots];
+        values[..self.nr_ins].copy_from_slice(inputs);
+        values[self.nr_ins] = 1.; // Bias node
+
+        for node in &self.nodes {
+            let mut sum = 0.;
+            for &(from, weight) in &node.incoming {
+                sum += values[from] * weight;
+            }
+            values[node.slot] = (5. * sum).tanh();
+        }
+
+        self.outputs.iter().map(|&slot| values[slot]).collect()
+    }
+
+    fn same_topology(&self, other: &Network) -> bool {
+        self.nr_ins == other.nr_ins
+            && self.outputs == other.outputs
+            && self.nodes.len() == other.nodes.len()
+            && self.nodes.iter().zip(other.nodes.iter()).all(|(a, b)| {
+                a.slot == b.slot
+                    && a.incoming.len() == b.incoming.len()
+                    && a.incoming
+                        .iter()
+                        .zip(b.incoming.iter())
+                        .all(|(x, y)| x.0 == y.0)
+            })
+    }
+}
+
+/// Networks sharing a topology, evaluated together. Values and weights are stored slot-major, so
+/// the inner loops run over contiguous memory, one element per network.
+struct Group {
+    members: Vec<usize>,
+    topology: Network,
+    weights: Vec<f64>, // [connection][member]
+    values: Vec<f64>,  // [slot][member]
+}
+
+/// Evaluates a whole population of networks at once
+pub struct BatchEvaluator {
+    pub nr_ins: usize,
+    pub nr_outs: usize,
+    len: usize,
+    groups: Vec<Group>,
+}
+
+impl BatchEvaluator {
+    pub fn new(networks: &[Network]) -> BatchEvaluator {
+        let mut groups: Vec<Group> = Vec::new();
+
+        for (i, network) in networks.iter().enumerate() {
+            match groups
+                .iter_mut()
+                .find(|x| x.topology.same_topology(network))
+            {
+                Some(group) => group.members.push(i),
+                None => groups.push(Group {
+                    members: vec![i],
+                    topology: network.clone(),
+                    weights: Vec::new(),
+                    values: Vec::new(),
+                }),
+            }
+        }
+
+        for group in &mut groups {
+            let m = group.members.len();
+            let nr_conns = group
+                .topology
+                .nodes
+                .iter()
+                .map(|x| x.incoming.len())
+                .sum::<usize>();
+
+            group.weights = vec![0.; nr_conns * m];
+            for (j, &member) in group.members.iter().enumerate() {
+                let weights = networks[member]
+                    .nodes
+                    .iter()
+                    .flat_map(|x| x.incoming.iter().map(|&(_, w)| w));
+                for (k, w) in weights.enumerate() {
+                    group.weights[k * m + j] = w;
+                }
+            }
+            group.values = vec![0.; group.topology.nr_slots * m];
+        }
+
+        let (nr_ins, nr_outs) = networks.first().map_or((0, 0), |x| (x.nr_ins, x.nr_outs));
+
+        BatchEvaluator {
+            nr_ins,
+            nr_outs,
+            len: networks.len(),
+            groups,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// `inputs` holds `nr_ins` values per network and `outputs` gets `nr_outs` per network, in
+    /// the order the networks were given.
+    pub fn evaluate(&mut self, inputs: &[f64], outputs: &mut [f64]) {
+        assert_eq!(inputs.len(), self.len * self.nr_ins);
+        assert_eq!(outputs.len(), self.len * self.nr_outs);
+
+        let (nr_ins, nr_outs) = (self.nr_ins, self.nr_outs);
+
+        for group in &mut self.groups {
+            let m = group.members.len();
+            let values = &mut group.values;
+
+            for (j, &member) in group.members.iter().enumerate() {
+                for i in 0..nr_ins {
+                    values[i * m + j] = inputs[member * nr_ins + i];
+                }
+                values[nr_ins * m + j] = 1.; // Bias node
+            }
+
+            let mut k = 0;
+            for node in &group.topology.nodes {
+                let (before, after) = values.split_at_mut(node.slot * m);
+                let acc = &mut after[..m];
+                for x in acc.iter_mut() {
+                    *x = 0.;
+                }
+
+                for &(from, _) in &node.incoming {
+                    let from = &before[from * m..(from + 1) * m];
+                    let weights = &group.weights[k * m..(k + 1) * m];
+                    for j in 0..m {
+                        acc[j] += from[j] * weights[j];
+                    }
+                    k += 1;
+                }
+
+                for x in acc.iter_mut() {
+                    *x = (5. * *x).tanh();
+                }
+            }
+
+            for (j, &member) in group.members.iter().enumerate() {
+                for (o, &slot) in group.topology.outputs.iter().enumerate() {
+                    outputs[member * nr_outs + o] = values[slot * m + j];
+                }
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn bench_batch() {
+    const CARS: usize = 100;
+    const FRAMES: usize = 1000;
+    const INS: usize = 6;
+    const OUTS: usize = 2;
+
+    let mut rng = thread_rng();
+
+    // A population in the state evolution leaves it: a few topologies shared by many genomes
+    let mut genomes = Vec::new();
+    for _ in 0..CARS / 10 {
+        let (mut genome, mut g_id) = Genome::init(INS, OUTS);
+        for _ in 0..5 {
+            genome.mutate_add_node(&mut g_id);
+            genome.mutate_add_connection(&mut g_id);
+        }
+        for _ in 0..10 {
+            let mut sibling = genome.clone();
+            for conn in sibling.connections.values_mut() {
+                conn.weight += rng.gen_range(-0.1, 0.1);
+            }
+            genomes.push(sibling);
+        }
+    }
+
+    let networks = genomes.iter().map(Network::compile).collect::<Vec<_>>();
+    let mut batch = BatchEvaluator::new(&networks);
+    println!(
+        "{} networks in {} groups",
+        networks.len(),
+        batch.groups.len()
+    );
+
+    let inputs = (0..CARS * INS)
+        .map(|_| rng.gen_range(0., 200.))
+        .collect::<Vec<f64>>();
+    let mut expected = vec![0.; CARS * OUTS];
+    let mut outputs = vec![0.; CARS * OUTS];
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        for (i, genome) in genomes.iter().enumerate() {
+            let res = genome.evaluate(&inputs[i * INS..(i + 1) * INS]);
+            expected[i * OUTS..(i + 1) * OUTS].copy_from_slice(&res);
+        }
+    }
+    println!("Genome::evaluate:  {:?}", start.elapsed() / FRAMES as u32);
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        for (i, network) in networks.iter().enumerate() {
+            let res = network.evaluate(&inputs[i * INS..(i + 1) * INS]);
+            outputs[i * OUTS..(i + 1) * OUTS].copy_from_slice(&res);
+        }
+    }
+    println!("Network::evaluate: {:?}", start.elapsed() / FRAMES as u32);
+    assert!(outputs
+        .iter()
+        .zip(&expected)
+        .all(|(a, b)| a.to_bits() == b.to_bits()));
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        batch.evaluate(&inputs, &mut outputs);
+    }
+    println!("BatchEvaluator:    {:?}", start.elapsed() / FRAMES as u32);
+    assert!(outputs
+        .iter()
+        .zip(&expected)
+        .all(|(a, b)| a.to_bits() == b.to_bits()));
+}
@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::neat::Genome;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpeciesRecord {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub born: usize,
+    pub extinct: Option<usize>,
+    pub sizes: Vec<usize>, // One entry per generation alive, starting at `born`
+}
+
+/// Species are only identified by their index in each generation, which shifts when species die
+/// out. This keeps a stable id for every species that has existed and where it branched from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Phylogeny {
+    pub species: Vec<SpeciesRecord>,
+    /// Stable id of each of the current species, by index
+    pub current: Vec<usize>,
+    pub generation: usize,
+    next_genome_id: usize,
+}
+
+impl Phylogeny {
+    /// Gives ids to a freshly initialized population, all belonging to one root species
+    pub fn init(&mut self, population: &mut [Genome]) {
+        for genome in population.iter_mut() {
+            genome.lineage.id = self.new_genome_id();
+        }
+        let root = self.new_species(None);
+        self.species[root].sizes.push(population.len());
+        self.current = vec![root];
+        self.generation += 1;
+    }
+
+    pub fn new_genome_id(&mut self) -> usize {
+        self.next_genome_id += 1;
+        self.next_genome_id
+    }
+
+    pub fn new_species(&mut self, parent: Option<usize>) -> usize {
+        let id = self.species.len();
+        self.species.push(SpeciesRecord {
+            id,
+            parent,
+            born: self.generation,
+            extinct: None,
+            sizes: Vec::new(),
+        });
+        id
+    }
+
+    pub fn species_id(&self, idx: usize) -> Option<usize> {
+        self.current.get(idx).cloned()
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut res = vec!["rankdir=\"LR\";".to_string()];
+        for sp in &self.species {
+            let extinct = match sp.extinct {
+                Some(gen) => format!("\\nextinct {}", gen),
+                None => String::new(),
+            };
+            res.push(format!(
+                "s{0} [label=\"s{0}\\nborn {1}{2}\\nmax size {3}\"];",
+                sp.id,
+                sp.born,
+                extinct,
+                sp.sizes.iter().max().unwrap_or(&0)
+            ));
+            if let Some(parent) = sp.parent {
+                res.push(format!("s{} -> s{};", parent, sp.id));
+            }
+        }
+        format!("digraph {{ {} }}", res.join(" "))
+    }
+
+    pub fn to_newick(&self) -> String {
+        let roots = self
+            .species
+            .iter()
+            .filter(|x| x.parent.is_none())
+            .map(|x| self.newick_subtree(x.id))
+            .collect::<Vec<_>>();
+
+        if roots.len() == 1 {
+            format!("{};", roots[0])
+        } else {
+            format!("({});", roots.join(","))
+        }
+    }
+
+    fn newick_subtree(&self, id: usize) -> String {
+        let sp = &self.species[id];
+        let children = self
+            .species
+            .iter()
+            .filter(|x| x.parent == Some(id))
+            .map(|x| self.newick_subtree(x.id))
+            .collect::<Vec<_>>();
+
+        let branch_length = match sp.parent {
+            Some(parent) => sp.born - self.species[parent].born,
+            None => sp.born,
+        };
+
+        if children.is_empty() {
+            format!("s{}:{}", id, branch_length)
+        } else {
+            format!("({})s{}:{}", children.join(","), id, branch_length)
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let species = self
+            .species
+            .iter()
+            .map(|sp| {
+                format!(
+                    "{{\"id\":{},\"parent\":{},\"born\":{},\"extinct\":{},\"sizes\":[{}]}}",
+                    sp.id,
+                    sp.parent.map_or("null".into(), |x| x.to_string()),
+                    sp.born,
+                    sp.extinct.map_or("null".into(), |x| x.to_string()),
+                    sp.sizes
+                        .iter()
+                        .map(usize::to_string)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"generation\":{},\"species\":[{}]}}",
+            self.generation,
+            species.join(",")
+        )
+    }
+
+    /// Writes `<prefix>.dot`, `<prefix>.nwk` and `<prefix>.json`
+    pub fn export(&self, prefix: &str) -> io::Result<()> {
+        write!(
+            File::create(format!("{}.dot", prefix))?,
+            "{}",
+            self.to_dot()
+        )?;
+        write!(
+            File::create(format!("{}.nwk", prefix))?,
+            "{}",
+            self.to_newick()
+        )?;
+        write!(
+            File::create(format!("{}.json", prefix))?,
+            "{}",
+            self.to_json()
+        )
+    }
+}
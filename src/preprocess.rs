@@ -0,0 +1,170 @@
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scale {
+    Unit,      // [0, 1]
+    Symmetric, // [-1, 1]
+}
+
+/// Mean and variance of everything seen so far, using Welford's algorithm
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunningStats {
+    pub count: u64,
+    pub mean: f64,
+    m2: f64,
+}
+
+impl RunningStats {
+    pub fn push(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count < 2 {
+            1.
+        } else {
+            (self.m2 / (self.count - 1) as f64).sqrt()
+        }
+    }
+}
+
+/// How a single network input is transformed before it's fed to the network. The raw value is
+/// first clamped to `[min, max]`, then optionally log scaled, then either scaled to `scale` or,
+/// if `running` is set, normalised to zero mean and unit variance over all values seen so far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Channel {
+    pub min: f64,
+    pub max: f64,
+    pub log: bool,
+    pub scale: Scale,
+    pub running: Option<RunningStats>,
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel {
+            min: 0.,
+            max: 1.,
+            log: false,
+            scale: Scale::Unit,
+            running: None,
+        }
+    }
+}
+
+impl Channel {
+    fn log_scale(&self, x: f64) -> f64 {
+        if self.log {
+            x.signum() * x.abs().ln_1p()
+        } else {
+            x
+        }
+    }
+
+    // What the running statistics are kept over
+    fn clamp_and_log(&self, x: f64) -> f64 {
+        self.log_scale(x.max(self.min).min(self.max))
+    }
+
+    pub fn process(&mut self, x: f64, learn: bool) -> f64 {
+        if learn {
+            let clamped = self.clamp_and_log(x);
+            if let Some(stats) = &mut self.running {
+                stats.push(clamped);
+            }
+        }
+        self.apply(x)
+    }
+
+    /// Like `process`, without updating the running statistics
+    pub fn apply(&self, x: f64) -> f64 {
+        let x = self.clamp_and_log(x);
+
+        if let Some(stats) = &self.running {
+            return (x - stats.mean) / stats.std_dev().max(1e-6);
+        }
+
+        let (min, max) = (self.log_scale(self.min), self.log_scale(self.max));
+        let unit = (x - min) / (max - min);
+        match self.scale {
+            Scale::Unit => unit,
+            Scale::Symmetric => unit * 2. - 1.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preprocessor {
+    pub channels: Vec<Channel>,
+    /// Whether running statistics are updated with the values being processed
+    pub learn: bool,
+}
+
+impl Preprocessor {
+    pub fn process(&mut self, inputs: &mut [f64]) {
+        assert_eq!(self.channels.len(), inputs.len());
+
+        for (channel, x) in self.channels.iter_mut().zip(inputs.iter_mut()) {
+            *x = channel.process(*x, self.learn);
+        }
+    }
+
+    /// Processes inputs that shouldn't shape the statistics, like those of cars not being evolved
+    pub fn process_without_learning(&self, inputs: &mut [f64]) {
+        assert_eq!(self.channels.len(), inputs.len());
+
+        for (channel, x) in self.channels.iter().zip(inputs.iter_mut()) {
+            *x = channel.apply(*x);
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn test_preprocess() {
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+    let channel = Channel {
+        min: 0.,
+        max: 10.,
+        ..Channel::default()
+    };
+    let mut preprocessor = Preprocessor {
+        channels: vec![
+            channel.clone(),
+            Channel {
+                scale: Scale::Symmetric,
+                ..channel.clone()
+            },
+            Channel {
+                running: Some(RunningStats::default()),
+                ..channel
+            },
+        ],
+        learn: true,
+    };
+
+    let mut inputs = [5., 20., 4.];
+    preprocessor.process(&mut inputs);
+    assert!(close(inputs[0], 0.5));
+    assert!(close(inputs[1], 1.)); // Clamped to the maximum
+    assert!(close(inputs[2], 0.)); // The only value seen is the mean
+
+    preprocessor.process(&mut [0., 0., 6.]);
+    let stats = preprocessor.channels[2].running.clone().unwrap();
+    assert_eq!(stats.count, 2);
+    assert!(close(stats.mean, 5.));
+
+    // Normalised the same way, but the statistics stay as they were
+    let mut inputs = [5., 20., 7.];
+    preprocessor.process_without_learning(&mut inputs);
+    assert!(close(inputs[0], 0.5));
+    assert!(close(inputs[2], 2. / 2f64.sqrt()));
+    let stats = preprocessor.channels[2].running.clone().unwrap();
+    assert_eq!(stats.count, 2);
+    assert!(close(stats.mean, 5.));
+
+    println!("test_preprocess: ok");
+}
@@ -0,0 +1,332 @@
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use bincode::{deserialize_from, serialize_into};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use serde_derive::{Deserialize, Serialize};
+use ytesrev::prelude::*;
+
+use crate::car_textures::*;
+use crate::game::{draw_car, Game};
+use crate::vehicle::VehicleState;
+
+/// One car at one simulation step. Stored as f32 to keep replays small.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    pub pos: (f32, f32),
+    pub dir: f32,
+    pub speed: f32,
+    pub inputs: Vec<f32>,  // Raw sensor values, before preprocessing
+    pub outputs: Vec<f32>, // Network outputs, empty for the human
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Track {
+    pub driver: String,         // `Controller::kind`
+    pub species: Option<usize>, // None for cars that aren't evolved
+    pub frames: Vec<Frame>,     // One per step, up to and including where the car stopped
+    pub stopped: bool,
+    pub died: bool,
+}
+
+/// Every car of one generation, step by step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub generation: usize,
+    pub map: String,
+    pub dt: f64,
+    pub tracks: Vec<Track>,
+}
+
+impl Replay {
+    pub fn new(generation: usize, map: &str, dt: f64, games: &[Game]) -> Replay {
+        let tracks = games
+            .iter()
+            .map(|game| Track {
+                driver: game.controller.kind().to_string(),
+                species: game.controller.species(),
+                frames: Vec::new(),
+                stopped: false,
+                died: false,
+            })
+            .collect();
+
+        Replay {
+            generation,
+            map: map.to_string(),
+            dt,
+            tracks,
+        }
+    }
+
+    /// Records a step for every car still running. `inputs` and `outputs` are indexed like the
+    /// games, empty where there's nothing to record.
+    pub fn record(&mut self, games: &[Game], inputs: &[Vec<f64>], outputs: &[Vec<f64>]) {
+        for (i, (game, track)) in games.iter().zip(self.tracks.iter_mut()).enumerate() {
+            if track.stopped {
+                continue;
+            }
+            let to_f32 = |x: &[f64]| x.iter().map(|&x| x as f32).collect();
+            track.frames.push(Frame {
+                pos: (game.car.pos.0 as f32, game.car.pos.1 as f32),
+                dir: game.car.dir as f32,
+                speed: game.car.speed as f32,
+                inputs: inputs.get(i).map_or(Vec::new(), |x| to_f32(x)),
+                outputs: outputs.get(i).map_or(Vec::new(), |x| to_f32(x)),
+            });
+            if !game.running() {
+                track.stopped = true;
+                track.died = game.died;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks
+            .iter()
+            .map(|x| x.frames.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Saves to `<dir>/<generation>.bc`, and removes the replays more than `keep` generations old
+    pub fn save(&self, dir: &str, keep: usize) -> io::Result<()> {
+        create_dir_all(dir)?;
+        let path = Path::new(dir).join(format!("{}.bc", self.generation));
+        serialize_into(BufWriter::new(File::create(path)?), self).map_err(io::Error::other)?;
+
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            let generation = path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .filter(|x| x.ends_with(".bc"))
+                .and_then(|x| x.trim_end_matches(".bc").parse::<usize>().ok());
+            if let Some(generation) = generation {
+                if generation + keep <= self.generation {
+                    remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> io::Result<Replay> {
+        deserialize_from(File::open(path)?).map_err(io::Error::other)
+    }
+
+    /// Writes one car's telemetry, a row per step
+    pub fn export_csv(&self, car: usize, path: &str) -> io::Result<()> {
+        let track = self.tracks.get(car).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No car {}, there's {}", car, self.tracks.len()),
+            )
+        })?;
+
+        let mut out = BufWriter::new(File::create(path)?);
+        let (nr_ins, nr_outs) = track
+            .frames
+            .first()
+            .map_or((0, 0), |x| (x.inputs.len(), x.outputs.len()));
+
+        write!(out, "step,time,x,y,dir,speed")?;
+        for i in 0..nr_ins {
+            write!(out, ",in{}", i)?;
+        }
+        for i in 0..nr_outs {
+            write!(out, ",out{}", i)?;
+        }
+        writeln!(out)?;
+
+        for (step, frame) in track.frames.iter().enumerate() {
+            write!(
+                out,
+                "{},{},{},{},{},{}",
+                step,
+                step as f64 * self.dt,
+                frame.pos.0,
+                frame.pos.1,
+                frame.dir,
+                frame.speed
+            )?;
+            for x in frame.inputs.iter().chain(frame.outputs.iter()) {
+                write!(out, ",{}", x)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Plays a replay back. Space pauses, left and right skip a second, comma and period step a
+/// single frame and up and down change the playback speed.
+pub struct ReplayScene {
+    pub replay: Replay,
+    pub im: PngImage,
+    pub time: f64,
+    pub paused: bool,
+    pub speed: f64,
+}
+
+impl ReplayScene {
+    pub fn new(replay: Replay, im: PngImage) -> ReplayScene {
+        ReplayScene {
+            replay,
+            im,
+            time: 0.,
+            paused: false,
+            speed: 1.,
+        }
+    }
+
+    fn seek(&mut self, time: f64) {
+        let end = self.replay.len().saturating_sub(1) as f64 * self.replay.dt;
+        self.time = time.max(0.).min(end);
+    }
+}
+
+impl Drawable for ReplayScene {
+    fn content(&self) -> Vec<&dyn Drawable> {
+        vec![]
+    }
+    fn content_mut(&mut self) -> Vec<&mut dyn Drawable> {
+        vec![]
+    }
+
+    fn step(&mut self) {}
+
+    fn state(&self) -> State {
+        State::Working
+    }
+
+    fn event(&mut self, event: Event) {
+        let dt = self.replay.dt;
+        if let Event::KeyDown {
+            keycode: Some(key), ..
+        } = event
+        {
+            match key {
+                Keycode::Space => self.paused = !self.paused,
+                Keycode::Left => self.seek(self.time - 1.),
+                Keycode::Right => self.seek(self.time + 1.),
+                Keycode::Comma => self.seek(self.time - dt),
+                Keycode::Period => self.seek(self.time + dt),
+                Keycode::Up => self.speed *= 2.,
+                Keycode::Down => self.speed /= 2.,
+                _ => return,
+            }
+            println!(
+                "{:.2}s / {:.2}s, {}x{}",
+                self.time,
+                self.replay.len() as f64 * dt,
+                self.speed,
+                if self.paused { ", paused" } else { "" }
+            );
+        }
+    }
+
+    fn update(&mut self, dt: f64) {
+        if !self.paused {
+            let time = self.time + dt * self.speed;
+            self.seek(time);
+        }
+    }
+
+    fn draw(&self, canvas: &mut Canvas<Window>, position: &Position, settings: DrawSettings) {
+        self.im.draw(canvas, position, settings);
+        let r = position.into_rect_with_size(self.im.width as u32, self.im.height as u32);
+        let step = (self.time / self.replay.dt).round() as usize;
+
+        for track in &self.replay.tracks {
+            let frame = match track.frames.get(step).or_else(|| track.frames.last()) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let crashed = track.died && step + 1 >= track.frames.len();
+            let car = VehicleState {
+                pos: (frame.pos.0 as f64, frame.pos.1 as f64),
+                dir: frame.dir as f64,
+                ..VehicleState::default()
+            };
+
+            match track.species {
+                Some(s) => {
+                    load_species(s);
+                    if let Ok(ref mut textures) = SPECIES_TEXTURES.lock() {
+                        let (texture, crash) = &textures[s];
+                        draw_car(canvas, r, &car, if crashed { crash } else { texture });
+                    }
+                }
+                None if track.driver == "human" => draw_car(canvas, r, &car, &CAR_TEXTURE_PLAYER),
+                None => draw_car(canvas, r, &car, &CAR_TEXTURE_AI),
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn test_replay() {
+    let dir = std::env::temp_dir().join("neat-driver-test-replay");
+    let dir = dir.to_str().unwrap();
+
+    let frame = |i: usize| Frame {
+        pos: (i as f32, 2. * i as f32),
+        dir: 0.5,
+        speed: 10.,
+        inputs: vec![1., 2., 3.],
+        outputs: vec![-1., 1.],
+    };
+    let replay = Replay {
+        generation: 7,
+        map: "map.png".to_string(),
+        dt: 0.02,
+        tracks: vec![
+            Track {
+                driver: "neat".to_string(),
+                species: Some(0),
+                frames: (0..50).map(frame).collect(),
+                stopped: true,
+                died: true,
+            },
+            Track {
+                driver: "human".to_string(),
+                species: None,
+                frames: (0..100).map(frame).collect(),
+                stopped: false,
+                died: false,
+            },
+        ],
+    };
+    replay.save(dir, 2).expect("Can't save replay");
+
+    let loaded = Replay::load(&format!("{}/7.bc", dir)).expect("Can't load replay");
+    assert_eq!(loaded.len(), 100);
+    assert_eq!(loaded.tracks[0].frames[49].pos, (49., 98.));
+    assert_eq!(loaded.tracks[1].driver, "human");
+    assert_eq!(loaded.tracks[1].species, None);
+
+    let csv = format!("{}/0.csv", dir);
+    loaded.export_csv(0, &csv).expect("Can't export telemetry");
+    let text = std::fs::read_to_string(&csv).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 51);
+    assert_eq!(lines[0], "step,time,x,y,dir,speed,in0,in1,in2,out0,out1");
+    assert_eq!(lines[2], "1,0.02,1,2,0.5,10,1,2,3,-1,1");
+    assert!(loaded.export_csv(2, &csv).is_err());
+
+    // Only the last two generations are kept
+    let later = Replay {
+        generation: 9,
+        ..loaded.clone()
+    };
+    later.save(dir, 2).expect("Can't save replay");
+    assert!(!Path::new(&format!("{}/7.bc", dir)).exists());
+    assert!(Path::new(&format!("{}/9.bc", dir)).exists());
+    assert!(Path::new(&csv).exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    println!("test_replay: ok");
+}
@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use bincode::deserialize_from;
+
+use crate::neat::Genome;
+use crate::stats::GenerationStats;
+
+const WIDTH: f64 = 800.;
+const HEIGHT: f64 = 300.;
+const MARGIN: f64 = 40.;
+
+const COLORS: &[&str] = &[
+    "#2471a3", "#c0392b", "#27ae60", "#8e44ad", "#d68910", "#17a589", "#7f8c8d", "#cb4335",
+];
+
+pub fn write_report(stats_path: &str, champion_path: &str, out_path: &str) -> io::Result<()> {
+    let stats = GenerationStats::read_all(stats_path)?;
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Run report</title>");
+    html.push_str("<style>body { font-family: sans-serif; margin: 2em; } svg { display: block; margin-bottom: 2em; }</style>");
+    html.push_str("</head><body><h1>Run report</h1>");
+
+    if let Some(last) = stats.last() {
+        let total_time = stats.iter().map(|x| x.wall_time).sum::<f64>();
+        html.push_str(&format!(
+            "<p>{} generations, {:.0} s total. Last generation: max fitness {:.3}, {} species, innovation {}.</p>",
+            stats.len(),
+            total_time,
+            last.max_fitness,
+            last.species_sizes.len(),
+            last.innovation
+        ));
+    }
+
+    html.push_str("<h2>Fitness</h2>");
+    let mut fitness = vec![
+        ("max", series(&stats, |x| x.max_fitness)),
+        ("median", series(&stats, |x| x.median_fitness)),
+        ("mean", series(&stats, |x| x.mean_fitness)),
+        ("min", series(&stats, |x| x.min_fitness)),
+    ];
+    let reference = stats
+        .iter()
+        .filter_map(|x| Some((x.generation as f64, x.reference_fitness?)))
+        .collect::<Vec<_>>();
+    if !reference.is_empty() {
+        fitness.push(("autopilot", reference));
+    }
+    html.push_str(&line_chart(&fitness));
+
+    html.push_str("<h2>Species sizes</h2>");
+    html.push_str(&stacked_chart(&stats));
+
+    html.push_str("<h2>Genome complexity</h2>");
+    html.push_str(&line_chart(&[
+        ("max enabled connections", series(&stats, |x| x.max_enabled as f64)),
+        ("mean enabled connections", series(&stats, |x| x.mean_enabled)),
+        ("max hidden nodes", series(&stats, |x| x.max_hidden as f64)),
+        ("mean hidden nodes", series(&stats, |x| x.mean_hidden)),
+    ]));
+
+    html.push_str("<h2>Champion</h2>");
+    let champion: Option<(usize, f64, Genome)> = File::open(champion_path)
+        .ok()
+        .and_then(|f| deserialize_from(f).ok());
+    match champion {
+        Some((generation, fitness, genome)) => {
+            html.push_str(&format!(
+                "<p>Generation {}, fitness {:.3}, {} hidden nodes, {} enabled connections.</p>",
+                generation,
+                fitness,
+                genome.hidden_nodes(),
+                genome.enabled_connections()
+            ));
+            html.push_str(&genome.to_svg());
+        }
+        None => {
+            html.push_str("<p>No champion checkpoint found.</p>");
+        }
+    }
+
+    html.push_str("</body></html>");
+
+    let mut f = File::create(out_path)?;
+    write!(f, "{}", html)
+}
+
+fn series<F: Fn(&GenerationStats) -> f64>(stats: &[GenerationStats], f: F) -> Vec<(f64, f64)> {
+    stats.iter().map(|x| (x.generation as f64, f(x))).collect()
+}
+
+fn svg_open(min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> String {
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\">\
+         <rect x=\"{m}\" y=\"{m}\" width=\"{iw}\" height=\"{ih}\" fill=\"none\" stroke=\"#999\"/>\
+         <text x=\"{m}\" y=\"{by}\" font-size=\"11\">{:.0}</text>\
+         <text x=\"{rx}\" y=\"{by}\" font-size=\"11\" text-anchor=\"end\">{:.0}</text>\
+         <text x=\"{lx}\" y=\"{ty}\" font-size=\"11\" text-anchor=\"end\">{:.2}</text>\
+         <text x=\"{lx}\" y=\"{bty}\" font-size=\"11\" text-anchor=\"end\">{:.2}</text>",
+        min_x,
+        max_x,
+        max_y,
+        min_y,
+        w = WIDTH + 2. * MARGIN,
+        h = HEIGHT + 2. * MARGIN,
+        m = MARGIN,
+        iw = WIDTH,
+        ih = HEIGHT,
+        by = HEIGHT + MARGIN + 15.,
+        rx = WIDTH + MARGIN,
+        lx = MARGIN - 4.,
+        ty = MARGIN + 10.,
+        bty = HEIGHT + MARGIN,
+    )
+}
+
+fn to_screen(p: (f64, f64), min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> (f64, f64) {
+    let x = MARGIN + (p.0 - min_x) / (max_x - min_x).max(1e-9) * WIDTH;
+    let y = MARGIN + HEIGHT - (p.1 - min_y) / (max_y - min_y).max(1e-9) * HEIGHT;
+    (x, y)
+}
+
+fn line_chart(lines: &[(&str, Vec<(f64, f64)>)]) -> String {
+    let points = lines.iter().flat_map(|(_, x)| x.iter().cloned());
+    let min_x = points.clone().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let max_x = points.clone().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max);
+    let min_y = points.clone().map(|p| p.1).fold(0., f64::min);
+    let max_y = points.map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+
+    if !min_x.is_finite() {
+        return "<p>No data.</p>".into();
+    }
+
+    let mut res = svg_open(min_x, max_x, min_y, max_y);
+    for (i, (name, line)) in lines.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let path = line
+            .iter()
+            .map(|&p| {
+                let (x, y) = to_screen(p, min_x, max_x, min_y, max_y);
+                format!("{:.1},{:.1}", x, y)
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        res.push_str(&format!(
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"/>\
+             <text x=\"{}\" y=\"{}\" font-size=\"11\" fill=\"{}\">{}</text>",
+            path,
+            color,
+            MARGIN + 5.,
+            MARGIN + 15. * (i + 1) as f64,
+            color,
+            name
+        ));
+    }
+    res.push_str("</svg>");
+    res
+}
+
+fn stacked_chart(stats: &[GenerationStats]) -> String {
+    if stats.is_empty() {
+        return "<p>No data.</p>".into();
+    }
+
+    let min_x = stats[0].generation as f64;
+    let max_x = stats[stats.len() - 1].generation as f64;
+    let max_y = stats
+        .iter()
+        .map(|x| x.species_sizes.iter().sum::<usize>())
+        .max()
+        .unwrap_or(1) as f64;
+    let nr_layers = stats.iter().map(|x| x.species_sizes.len()).max().unwrap_or(0);
+
+    let mut res = svg_open(min_x, max_x, 0., max_y);
+
+    // Species are stacked by their index in each generation, bottom to top
+    for layer in 0..nr_layers {
+        let bounds = stats
+            .iter()
+            .map(|x| {
+                let below = x.species_sizes.iter().take(layer).sum::<usize>() as f64;
+                let size = x.species_sizes.get(layer).cloned().unwrap_or(0) as f64;
+                (x.generation as f64, below, below + size)
+            })
+            .collect::<Vec<_>>();
+
+        let mut points = Vec::new();
+        for &(g, _, top) in &bounds {
+            points.push(to_screen((g, top), min_x, max_x, 0., max_y));
+        }
+        for &(g, bottom, _) in bounds.iter().rev() {
+            points.push(to_screen((g, bottom), min_x, max_x, 0., max_y));
+        }
+
+        res.push_str(&format!(
+            "<polygon points=\"{}\" fill=\"{}\" stroke=\"none\" opacity=\"0.8\"/>",
+            points
+                .iter()
+                .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+                .collect::<Vec<_>>()
+                .join(" "),
+            COLORS[layer % COLORS.len()]
+        ));
+    }
+    res.push_str("</svg>");
+    res
+}
@@ -0,0 +1,139 @@
+use std::f64::consts::PI;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::map::{Map, RayHit};
+use crate::preprocess::Channel;
+use crate::vehicle::{Body, VehicleState};
+
+// The ranges extra sensors are expected to stay in, used to scale them for the network
+pub const MAX_SPEED: f64 = 300.;
+pub const MAX_YAW_RATE: f64 = 10.;
+pub const MAX_CLEARANCE: f64 = 50.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Extra {
+    /// Forward speed, in pixels per second
+    Speed,
+    /// Angle between the car and the direction the track goes, in [-π, π]. Zero when driving
+    /// straight away from the start, ±π when driving back towards it.
+    Heading,
+    /// How fast the car is turning, in radians per second
+    AngularVelocity,
+    /// Distance from the center of the car to the nearest wall, in any direction
+    Clearance,
+}
+
+/// Everything a car senses. The network gets the extras first, in order, followed by the
+/// distance to the nearest wall along each ray, from the leftmost ray to the rightmost, and then,
+/// with `car_rays`, the distance to the nearest other car along each ray.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensorSuite {
+    pub nr_rays: usize,
+    pub spread: f64,    // Angle between the outermost rays, in radians
+    pub max_range: f64, // Rays stop this far from the car, in pixels
+    pub extras: Vec<Extra>,
+    pub car_rays: bool,
+}
+
+impl Default for SensorSuite {
+    // Speed and five rays over a half circle, what the cars have always had
+    fn default() -> SensorSuite {
+        SensorSuite {
+            nr_rays: 5,
+            spread: PI,
+            max_range: 300.,
+            extras: vec![Extra::Speed],
+            car_rays: false,
+        }
+    }
+}
+
+impl SensorSuite {
+    pub fn nr_inputs(&self) -> usize {
+        let per_ray = if self.car_rays { 2 } else { 1 };
+        self.extras.len() + self.nr_rays * per_ray
+    }
+
+    /// The direction of each ray relative to the car
+    pub fn ray_angles(&self) -> Vec<f64> {
+        if self.nr_rays == 1 {
+            return vec![0.];
+        }
+        (0..self.nr_rays)
+            .map(|i| (i as f64 / (self.nr_rays - 1) as f64 - 0.5) * self.spread)
+            .collect()
+    }
+
+    /// Where each ray stops, either at a wall or at `max_range`
+    pub fn cast_rays(&self, map: &Map, car: &VehicleState) -> Vec<RayHit> {
+        self.ray_angles()
+            .into_iter()
+            .map(|angle| map.cast_ray(car.pos, car.dir + angle, self.max_range))
+            .collect()
+    }
+
+    pub fn sense(&self, map: &Map, car: &VehicleState, others: &[Body]) -> Vec<f64> {
+        let mut inputs = Vec::with_capacity(self.nr_inputs());
+
+        for extra in &self.extras {
+            inputs.push(match extra {
+                Extra::Speed => car.speed,
+                Extra::Heading => match map.track_direction(car.pos) {
+                    Some(track) => {
+                        let diff = (car.dir - track) % (2. * PI);
+                        if diff > PI {
+                            diff - 2. * PI
+                        } else if diff < -PI {
+                            diff + 2. * PI
+                        } else {
+                            diff
+                        }
+                    }
+                    None => 0.,
+                },
+                Extra::AngularVelocity => car.yaw_rate,
+                Extra::Clearance => map.clearance(car.pos),
+            });
+        }
+
+        for hit in self.cast_rays(map, car) {
+            inputs.push(hit.dist);
+        }
+
+        if self.car_rays {
+            for angle in self.ray_angles() {
+                let nearest = others
+                    .iter()
+                    .filter_map(|x| x.ray_hit(car.pos, car.dir + angle, self.max_range))
+                    .fold(self.max_range, f64::min);
+                inputs.push(nearest);
+            }
+        }
+
+        inputs
+    }
+
+    /// Preprocessing channels covering the range of each input, based on `channel`
+    pub fn channels(&self, channel: &Channel) -> Vec<Channel> {
+        let range = |min, max| Channel {
+            min,
+            max,
+            ..channel.clone()
+        };
+
+        let mut channels = Vec::with_capacity(self.nr_inputs());
+        for extra in &self.extras {
+            channels.push(match extra {
+                Extra::Speed => range(-MAX_SPEED, MAX_SPEED),
+                Extra::Heading => range(-PI, PI),
+                Extra::AngularVelocity => range(-MAX_YAW_RATE, MAX_YAW_RATE),
+                Extra::Clearance => range(0., MAX_CLEARANCE),
+            });
+        }
+        for _ in self.extras.len()..self.nr_inputs() {
+            channels.push(range(0., self.max_range));
+        }
+        channels
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::neat::Genome;
+
+const HEADER: &str = "generation,min_fitness,mean_fitness,median_fitness,max_fitness,\
+species_count,species_sizes,mean_hidden,max_hidden,mean_enabled,max_enabled,innovation,wall_time,\
+reference_fitness";
+
+#[derive(Debug, Clone)]
+pub struct GenerationStats {
+    pub generation: usize,
+    pub min_fitness: f64,
+    pub mean_fitness: f64,
+    pub median_fitness: f64,
+    pub max_fitness: f64,
+    pub species_sizes: Vec<usize>,
+    pub mean_hidden: f64,
+    pub max_hidden: usize,
+    pub mean_enabled: f64,
+    pub max_enabled: usize,
+    pub innovation: usize,
+    pub wall_time: f64,                 // In seconds
+    pub reference_fitness: Option<f64>, // The autopilot's, if it drove
+}
+
+impl GenerationStats {
+    pub fn collect(
+        generation: usize,
+        species: &[Vec<(Genome, usize)>],
+        fitnesses: &[f64],
+        innovation: usize,
+        wall_time: f64,
+    ) -> GenerationStats {
+        let mut sorted = fitnesses.to_vec();
+        sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let median_fitness = match sorted.len() {
+            0 => 0.,
+            n if n % 2 == 0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.,
+            n => sorted[n / 2],
+        };
+
+        let genomes = species.iter().flatten().map(|(genome, _)| genome);
+        let hidden = genomes.clone().map(Genome::hidden_nodes).collect::<Vec<_>>();
+        let enabled = genomes.map(Genome::enabled_connections).collect::<Vec<_>>();
+
+        GenerationStats {
+            generation,
+            min_fitness: sorted.first().cloned().unwrap_or(0.),
+            mean_fitness: mean(&sorted),
+            median_fitness,
+            max_fitness: sorted.last().cloned().unwrap_or(0.),
+            species_sizes: species.iter().map(Vec::len).collect(),
+            mean_hidden: mean(&hidden.iter().map(|&x| x as f64).collect::<Vec<_>>()),
+            max_hidden: hidden.iter().cloned().max().unwrap_or(0),
+            mean_enabled: mean(&enabled.iter().map(|&x| x as f64).collect::<Vec<_>>()),
+            max_enabled: enabled.iter().cloned().max().unwrap_or(0),
+            innovation,
+            wall_time,
+            reference_fitness: None,
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.generation,
+            self.min_fitness,
+            self.mean_fitness,
+            self.median_fitness,
+            self.max_fitness,
+            self.species_sizes.len(),
+            self.species_sizes
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+            self.mean_hidden,
+            self.max_hidden,
+            self.mean_enabled,
+            self.max_enabled,
+            self.innovation,
+            self.wall_time,
+            self.reference_fitness
+                .map_or(String::new(), |x| x.to_string()),
+        )
+    }
+
+    pub fn from_csv(line: &str) -> Option<GenerationStats> {
+        let fields = line.split(',').collect::<Vec<_>>();
+        // Files from before the reference car have one field less
+        if fields.len() != 13 && fields.len() != 14 {
+            return None;
+        }
+
+        Some(GenerationStats {
+            generation: fields[0].parse().ok()?,
+            min_fitness: fields[1].parse().ok()?,
+            mean_fitness: fields[2].parse().ok()?,
+            median_fitness: fields[3].parse().ok()?,
+            max_fitness: fields[4].parse().ok()?,
+            species_sizes: fields[6]
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?,
+            mean_hidden: fields[7].parse().ok()?,
+            max_hidden: fields[8].parse().ok()?,
+            mean_enabled: fields[9].parse().ok()?,
+            max_enabled: fields[10].parse().ok()?,
+            innovation: fields[11].parse().ok()?,
+            wall_time: fields[12].parse().ok()?,
+            reference_fitness: fields.get(13).and_then(|x| x.parse().ok()),
+        })
+    }
+
+    pub fn append_to(&self, path: &str) -> io::Result<()> {
+        let is_new = File::open(path).is_err();
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        if is_new {
+            writeln!(f, "{}", HEADER)?;
+        }
+        writeln!(f, "{}", self.to_csv())
+    }
+
+    pub fn read_all(path: &str) -> io::Result<Vec<GenerationStats>> {
+        let f = File::open(path)?;
+        let mut res = Vec::new();
+        for line in BufReader::new(f).lines() {
+            if let Some(stats) = GenerationStats::from_csv(&line?) {
+                res.push(stats);
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn mean(xs: &[f64]) -> f64 {
+    if xs.is_empty() {
+        0.
+    } else {
+        xs.iter().sum::<f64>() / xs.len() as f64
+    }
+}
@@ -0,0 +1,159 @@
+use std::f64::consts::PI;
+
+/// Why a car stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    Crashed,     // Into a wall
+    OutOfBounds, // Off the edge of the map
+    Collision,   // With another car
+    Finished,    // Drove all its laps
+    Stagnant,    // No progress for too long
+    Backwards,   // Lost progress for too long
+    Spinning,    // Turned around too much without getting anywhere
+    Timeout,     // Reached the longest episode time
+}
+
+impl Termination {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Termination::Crashed => "wall",
+            Termination::OutOfBounds => "out of bounds",
+            Termination::Collision => "collision",
+            Termination::Finished => "finished",
+            Termination::Stagnant => "stagnation",
+            Termination::Backwards => "backwards",
+            Termination::Spinning => "spinning",
+            Termination::Timeout => "timeout",
+        }
+    }
+
+    /// Whether the car is wrecked, rather than just stopped
+    pub fn crashed(&self) -> bool {
+        matches!(
+            self,
+            Termination::Crashed | Termination::OutOfBounds | Termination::Collision
+        )
+    }
+}
+
+/// Limits that stop a car early, in seconds unless noted. None turns a rule off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerminationRules {
+    pub stagnation: Option<f64>, // Without beating the car's best progress
+    pub backwards: Option<f64>,  // Losing progress every step
+    pub spinning: Option<f64>,   // Radians turned, either way, since the last new best progress
+    pub max_time: Option<f64>,
+}
+
+pub const DEFAULT_RULES: TerminationRules = TerminationRules {
+    stagnation: Some(5.),
+    backwards: Some(2.),
+    spinning: Some(4. * PI),
+    max_time: Some(120.),
+};
+
+/// Keeps track of what the rules need, step by step
+#[derive(Debug, Clone, Default)]
+pub struct Watchdog {
+    best_progress: f64,
+    best_time: f64, // When `best_progress` was reached
+    last_progress: f64,
+    backwards_time: f64,
+    turned: f64,
+}
+
+impl Watchdog {
+    /// Called after every step with the car's total progress and how much it turned
+    pub fn check(
+        &mut self,
+        rules: &TerminationRules,
+        time: f64,
+        dt: f64,
+        progress: f64,
+        turned: f64,
+    ) -> Option<Termination> {
+        if progress > self.best_progress {
+            self.best_progress = progress;
+            self.best_time = time;
+            self.turned = 0.;
+        } else {
+            self.turned += turned.abs();
+        }
+
+        if progress < self.last_progress {
+            self.backwards_time += dt;
+        } else if progress > self.last_progress {
+            self.backwards_time = 0.;
+        }
+        self.last_progress = progress;
+
+        let exceeds = |limit: Option<f64>, x: f64| limit.is_some_and(|limit| x > limit);
+        if exceeds(rules.max_time, time) {
+            Some(Termination::Timeout)
+        } else if exceeds(rules.backwards, self.backwards_time) {
+            Some(Termination::Backwards)
+        } else if exceeds(rules.spinning, self.turned) {
+            Some(Termination::Spinning)
+        } else if exceeds(rules.stagnation, time - self.best_time) {
+            Some(Termination::Stagnant)
+        } else {
+            None
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn test_termination() {
+    const DT: f64 = 0.02;
+
+    // Runs the watchdog with progress and turning given per step, returning when it stopped
+    let run = |rules: &TerminationRules, step: &dyn Fn(usize) -> (f64, f64)| {
+        let mut watchdog = Watchdog::default();
+        for i in 1..100_000 {
+            let (progress, turned) = step(i);
+            if let Some(reason) = watchdog.check(rules, i as f64 * DT, DT, progress, turned) {
+                return Some((reason, i as f64 * DT));
+            }
+        }
+        None
+    };
+    let near = |res: Option<(Termination, f64)>, reason, time: f64| match res {
+        Some((r, t)) => r == reason && (t - time).abs() < 2. * DT,
+        None => false,
+    };
+
+    // Steady progress only stops at the time limit
+    assert!(near(
+        run(&DEFAULT_RULES, &|i| (i as f64, 0.)),
+        Termination::Timeout,
+        120.
+    ));
+    let no_limit = TerminationRules {
+        max_time: None,
+        ..DEFAULT_RULES
+    };
+    assert_eq!(run(&no_limit, &|i| (i as f64, 0.01)), None);
+
+    // Driving forwards for ten seconds, then standing still, backing up or circling
+    let forwards = |i: usize| i.min(500) as f64;
+    assert!(near(
+        run(&no_limit, &|i| (forwards(i), 0.)),
+        Termination::Stagnant,
+        15.
+    ));
+    assert!(near(
+        run(&no_limit, &|i| (500. - (i as f64 - 500.).abs(), 0.)),
+        Termination::Backwards,
+        12.
+    ));
+    assert!(near(
+        run(&no_limit, &|i| (forwards(i), PI * DT)),
+        Termination::Spinning,
+        14.
+    ));
+
+    // Going back and forth isn't going backwards
+    let wobble = |i: usize| (((i / 20) % 2) as f64, 0.);
+    assert!(near(run(&no_limit, &wobble), Termination::Stagnant, 5.4));
+    println!("test_termination: ok");
+}
@@ -0,0 +1,218 @@
+use std::f64::consts::PI;
+
+use crate::control::Action;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VehicleState {
+    pub pos: (f64, f64),
+    pub dir: f64,      // In radians, 0 = right
+    pub speed: f64,    // Along `dir`, in pixels per second
+    pub slip: f64,     // Sideways, only nonzero while drifting
+    pub yaw_rate: f64, // Radians per second
+}
+
+impl VehicleState {
+    pub fn at(pos: (f64, f64), dir: f64) -> VehicleState {
+        VehicleState {
+            pos,
+            dir,
+            ..VehicleState::default()
+        }
+    }
+
+    pub fn velocity(&self) -> (f64, f64) {
+        let (sin, cos) = self.dir.sin_cos();
+        (
+            self.speed * cos - self.slip * sin,
+            self.speed * sin + self.slip * cos,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dynamics {
+    pub max_accel: f64,    // Pixels per second squared at full throttle
+    pub max_brake: f64,    // Deceleration at full brake
+    pub drag: f64,         // Quadratic air resistance
+    pub rolling: f64,      // Linear rolling resistance
+    pub wheelbase: f64,    // In pixels
+    pub max_steer: f64,    // Front wheel angle at full lock, in radians
+    pub grip: Option<f64>, // Largest sideways acceleration the tyres can give, or perfect grip
+}
+
+pub const DEFAULT_DYNAMICS: Dynamics = Dynamics {
+    max_accel: 60.,
+    max_brake: 150.,
+    drag: 0.0015,
+    rolling: 0.1,
+    wheelbase: 20.,
+    max_steer: 0.5,
+    grip: Some(300.),
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Vehicle {
+    /// The car moves exactly along its heading and can turn at a fixed rate, even standing still
+    Kinematic,
+    /// Acceleration and braking limits, drag, bicycle model steering and optional tyre grip
+    Dynamic(Dynamics),
+}
+
+// Kinematic parameters, the same as before there was a vehicle model
+pub const MAX_ACCEL: f64 = 40.;
+pub const BRAKE_DECEL: f64 = 80.;
+pub const STEER_RATE: f64 = 10.;
+
+/// Moves `speed` towards zero by at most `amount`, without passing it
+fn towards_zero(speed: f64, amount: f64) -> f64 {
+    if speed.abs() <= amount {
+        0.
+    } else {
+        speed - amount * speed.signum()
+    }
+}
+
+impl Vehicle {
+    pub fn step(&self, state: &mut VehicleState, action: Action, dt: f64) {
+        match self {
+            Vehicle::Kinematic => {
+                state.pos.0 += state.speed * state.dir.cos() * dt;
+                state.pos.1 += state.speed * state.dir.sin() * dt;
+
+                state.speed += action.throttle * MAX_ACCEL * dt;
+                state.speed = towards_zero(state.speed, action.brake * BRAKE_DECEL * dt);
+                state.yaw_rate = action.steer * STEER_RATE;
+                state.dir += state.yaw_rate * dt;
+            }
+            Vehicle::Dynamic(params) => {
+                // Longitudinal forces
+                state.speed += action.throttle * params.max_accel * dt;
+                let resistance =
+                    params.drag * state.speed * state.speed + params.rolling * state.speed.abs();
+                state.speed = towards_zero(state.speed, action.brake * params.max_brake * dt);
+                state.speed = towards_zero(state.speed, resistance * dt);
+
+                // Bicycle model, the turning radius is wheelbase / tan(steering angle)
+                let steer_angle = action.steer.clamp(-1., 1.) * params.max_steer;
+                state.yaw_rate = state.speed * steer_angle.tan() / params.wheelbase;
+
+                match params.grip {
+                    None => {
+                        state.dir += state.yaw_rate * dt;
+                        state.slip = 0.;
+                    }
+                    Some(grip) => {
+                        // The body turns, the velocity stays behind. The tyres can only remove
+                        // so much of the resulting sideways velocity each step, the rest drifts.
+                        let velocity = state.velocity();
+                        state.dir += state.yaw_rate * dt;
+                        let (sin, cos) = state.dir.sin_cos();
+                        let forward = velocity.0 * cos + velocity.1 * sin;
+                        let sideways = -velocity.0 * sin + velocity.1 * cos;
+
+                        state.speed = forward;
+                        state.slip = towards_zero(sideways, grip * dt);
+                    }
+                }
+
+                let velocity = state.velocity();
+                state.pos.0 += velocity.0 * dt;
+                state.pos.1 += velocity.1 * dt;
+            }
+        }
+    }
+}
+
+/// The rectangle a car takes up, `size.0` long along its direction and `size.1` wide
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Body {
+    pub state: VehicleState,
+    pub size: (f64, f64),
+}
+
+impl Body {
+    fn axes(&self) -> [(f64, f64); 2] {
+        let (sin, cos) = self.state.dir.sin_cos();
+        [(cos, sin), (-sin, cos)]
+    }
+
+    /// How far along the ray from `from` the body is, if it's in front of it and closer than
+    /// `max_range`. Rays starting inside the body don't see it, so a car never sees itself.
+    pub fn ray_hit(&self, from: (f64, f64), angle: f64, max_range: f64) -> Option<f64> {
+        let rel = (from.0 - self.state.pos.0, from.1 - self.state.pos.1);
+        let dir = (angle.cos(), angle.sin());
+        let half = [self.size.0 / 2., self.size.1 / 2.];
+
+        let (mut enter, mut exit) = (f64::NEG_INFINITY, f64::INFINITY);
+        for (axis, half) in self.axes().iter().zip(half.iter()) {
+            let origin = rel.0 * axis.0 + rel.1 * axis.1;
+            let speed = dir.0 * axis.0 + dir.1 * axis.1;
+            if speed.abs() < 1e-12 {
+                if origin.abs() > *half {
+                    return None;
+                }
+                continue;
+            }
+            let (t1, t2) = ((-half - origin) / speed, (half - origin) / speed);
+            enter = enter.max(t1.min(t2));
+            exit = exit.min(t1.max(t2));
+        }
+
+        if enter > exit || enter <= 0. || enter > max_range {
+            None
+        } else {
+            Some(enter)
+        }
+    }
+
+    /// Whether the two rectangles overlap, by the separating axis theorem
+    pub fn overlaps(&self, other: &Body) -> bool {
+        let d = (
+            other.state.pos.0 - self.state.pos.0,
+            other.state.pos.1 - self.state.pos.1,
+        );
+        let reach = |x: &Body| (x.size.0.powi(2) + x.size.1.powi(2)).sqrt() / 2.;
+        if d.0 * d.0 + d.1 * d.1 > (reach(self) + reach(other)).powi(2) {
+            return false;
+        }
+
+        let radius = |x: &Body, axis: (f64, f64)| {
+            let [u, v] = x.axes();
+            x.size.0 / 2. * (u.0 * axis.0 + u.1 * axis.1).abs()
+                + x.size.1 / 2. * (v.0 * axis.0 + v.1 * axis.1).abs()
+        };
+        self.axes().iter().chain(other.axes().iter()).all(|&axis| {
+            (d.0 * axis.0 + d.1 * axis.1).abs() <= radius(self, axis) + radius(other, axis)
+        })
+    }
+}
+
+#[allow(unused)]
+pub fn test_bodies() {
+    let body = |x: f64, y: f64, dir: f64| Body {
+        state: VehicleState::at((x, y), dir),
+        size: (30., 10.),
+    };
+    let car = body(100., 100., 0.);
+    let eq = |a: Option<f64>, b: f64| (a.expect("Missed") - b).abs() < 1e-9;
+
+    // Front, side and corner on
+    assert!(eq(car.ray_hit((0., 100.), 0., 1000.), 85.));
+    assert!(eq(car.ray_hit((100., 0.), PI / 2., 1000.), 95.));
+    assert!(eq(
+        body(100., 100., PI / 2.).ray_hit((0., 100.), 0., 1000.),
+        95.
+    ));
+    assert!(car.ray_hit((0., 100.), 0., 50.).is_none());
+    assert!(car.ray_hit((0., 100.), PI, 1000.).is_none());
+    assert!(car.ray_hit((0., 106.), 0., 1000.).is_none());
+    assert!(car.ray_hit((100., 100.), 0., 1000.).is_none()); // From inside
+
+    assert!(car.overlaps(&body(125., 100., 0.)));
+    assert!(!car.overlaps(&body(131., 100., 0.)));
+    assert!(car.overlaps(&body(100., 105., PI / 2.)));
+    assert!(!car.overlaps(&body(100., 121., PI / 2.)));
+    // The bounding circles overlap but the rectangles don't
+    assert!(!car.overlaps(&body(128., 112., PI / 2.)));
+    println!("test_bodies: ok");
+}